@@ -0,0 +1,60 @@
+use axum::async_trait;
+use http::{HeaderMap, Method};
+use serde_json::Value;
+
+/// Request being authorised, as seen by an `AuthorizationEngine`
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    /// Method of the request
+    pub method: Method,
+    /// Path of the request, without the query
+    pub path: String,
+    /// Headers of the request
+    pub headers: HeaderMap,
+}
+
+/// Decision of an `AuthorizationEngine` on a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// The request is authorised
+    Allow,
+    /// The request is denied for the given reason and gets a 403, the reason is logged but not
+    /// returned to the client
+    Deny(String),
+}
+
+/// Decides whether a request with a verified token is authorised, e.g. by delegating to a policy
+/// engine such as Cedar or an OPA sidecar
+///
+/// The engine is called by the `CognitoAuthLayer` once the token is verified and the authorization
+/// rules are satisfied.
+///
+/// # Example
+/// ```rust
+/// # use axum_cognito::{AuthorizationEngine, Decision, RequestContext};
+/// struct DenyAdmin;
+///
+/// #[axum::async_trait]
+/// impl AuthorizationEngine for DenyAdmin {
+///     async fn authorize(
+///         &self,
+///         _claims: &serde_json::Value,
+///         request: &RequestContext,
+///     ) -> Decision {
+///         if request.path.starts_with("/admin") {
+///             Decision::Deny("admin routes are disabled".to_string())
+///         } else {
+///             Decision::Allow
+///         }
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait AuthorizationEngine: Send + Sync {
+    /// Decide whether the request is authorised
+    ///
+    /// # Arguments
+    /// * `claims` - raw claims of the verified token
+    /// * `request` - request being authorised
+    async fn authorize(&self, claims: &Value, request: &RequestContext) -> Decision;
+}
@@ -0,0 +1,189 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    cognito_validator::{numeric_timestamp, unix_timestamp},
+    Scopes,
+};
+
+/// Normalizes a group name before group rules compare it, e.g. lowercasing it or stripping an
+/// environment prefix
+pub type GroupNormalizer = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Rule the claims of a verified token must satisfy for a request to be authorised
+///
+/// Rules serialize as `{"rule": "group", "value": "admins"}`, e.g. to render the effective policy
+/// of a layer on a diagnostics endpoint
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "rule", content = "value", rename_all = "snake_case")]
+pub enum AuthorizationRule {
+    /// The unix timestamp held by the claim must be in the past
+    ClaimTimePassed(String),
+    /// The user must belong to the group, listed in the `cognito:groups` claim
+    Group(String),
+    /// The token must be granted at least one scope of the resource server, i.e. a scope
+    /// starting with the resource server identifier prefix
+    ResourceServer(String),
+    /// The token must be issued to the client and granted the scope, both checked together
+    ///
+    /// The client id is read from the `client_id` claim of access tokens, falling back to the
+    /// `aud` claim of ID tokens
+    ClientAndScope { client_id: String, scope: String },
+}
+
+impl AuthorizationRule {
+    /// Check whether the claims of a verified token satisfy the rule
+    ///
+    /// The group normalizer, if any, is applied to both the required group and the groups of the
+    /// token
+    pub(crate) fn is_satisfied_by(
+        &self,
+        claims: &Value,
+        group_normalizer: Option<&GroupNormalizer>,
+    ) -> bool {
+        match self {
+            Self::ClaimTimePassed(claim) => claims
+                .get(claim)
+                .and_then(numeric_timestamp)
+                .is_some_and(|timestamp| timestamp <= unix_timestamp()),
+            Self::Group(group) => {
+                let normalize = |group: &str| match group_normalizer {
+                    Some(group_normalizer) => group_normalizer(group),
+                    None => group.to_string(),
+                };
+                let group = normalize(group);
+                claims
+                    .get("cognito:groups")
+                    .and_then(Value::as_array)
+                    .is_some_and(|groups| {
+                        groups
+                            .iter()
+                            .filter_map(Value::as_str)
+                            .any(|member_of| normalize(member_of) == group)
+                    })
+            }
+            Self::ResourceServer(prefix) => Scopes::from_claims(claims)
+                .0
+                .iter()
+                .any(|scope| scope.starts_with(prefix.as_str())),
+            Self::ClientAndScope { client_id, scope } => {
+                let issued_to_client = match claims.get("client_id").or_else(|| claims.get("aud")) {
+                    Some(Value::String(audience)) => audience == client_id,
+                    Some(Value::Array(audience)) => audience
+                        .iter()
+                        .any(|audience| audience.as_str() == Some(client_id.as_str())),
+                    _ => false,
+                };
+                issued_to_client && Scopes::from_claims(claims).contains(scope)
+            }
+        }
+    }
+
+    /// Whether the claim the rule is checked against is entirely absent from the claims
+    pub(crate) fn is_claim_missing(&self, claims: &Value) -> bool {
+        match self {
+            Self::ClaimTimePassed(claim) => claims.get(claim).is_none(),
+            Self::Group(_) => claims.get("cognito:groups").is_none(),
+            Self::ResourceServer(_) => is_scope_missing(claims),
+            Self::ClientAndScope { .. } => {
+                (claims.get("client_id").is_none() && claims.get("aud").is_none())
+                    || is_scope_missing(claims)
+            }
+        }
+    }
+}
+
+fn is_scope_missing(claims: &Value) -> bool {
+    claims.get("scope").is_none() && claims.get("scp").is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn claim_time_passed_requires_a_timestamp_in_the_past() {
+        let rule = AuthorizationRule::ClaimTimePassed("activate_at".to_string());
+        let now = unix_timestamp();
+
+        assert!(rule.is_satisfied_by(&json!({ "activate_at": now - 60 }), None));
+        assert!(!rule.is_satisfied_by(&json!({ "activate_at": now + 60 }), None));
+        assert!(!rule.is_satisfied_by(&json!({ "activate_at": "soon" }), None));
+        assert!(!rule.is_satisfied_by(&json!({}), None));
+    }
+
+    #[test]
+    fn group_normalizer_applies_to_required_and_token_groups() {
+        let rule = AuthorizationRule::Group("Admins".to_string());
+        let claims = json!({ "cognito:groups": ["prod-admins"] });
+        let normalizer: GroupNormalizer =
+            Arc::new(|group: &str| group.trim_start_matches("prod-").to_lowercase());
+
+        assert!(!rule.is_satisfied_by(&claims, None));
+        assert!(rule.is_satisfied_by(&claims, Some(&normalizer)));
+    }
+
+    #[test]
+    fn resource_server_rule_requires_a_scope_of_the_resource_server() {
+        let rule = AuthorizationRule::ResourceServer("https://api.example.com/".to_string());
+
+        assert!(rule.is_satisfied_by(
+            &json!({ "scope": "openid https://api.example.com/read" }),
+            None
+        ));
+        assert!(!rule.is_satisfied_by(
+            &json!({ "scope": "openid https://other.example.com/read" }),
+            None
+        ));
+        assert!(!rule.is_satisfied_by(&json!({}), None));
+    }
+
+    #[test]
+    fn resource_server_rule_reads_the_scopes_of_the_scp_claim() {
+        let rule = AuthorizationRule::ResourceServer("https://api.example.com/".to_string());
+        let claims = json!({ "scp": ["openid", "https://api.example.com/read"] });
+
+        assert!(rule.is_satisfied_by(&claims, None));
+        assert!(!rule.is_claim_missing(&claims));
+    }
+
+    #[test]
+    fn client_and_scope_rule_requires_both_the_client_and_the_scope() {
+        let rule = AuthorizationRule::ClientAndScope {
+            client_id: "reporting".to_string(),
+            scope: "https://api.example.com/read".to_string(),
+        };
+
+        assert!(rule.is_satisfied_by(
+            &json!({ "client_id": "reporting", "scope": "https://api.example.com/read" }),
+            None
+        ));
+        assert!(!rule.is_satisfied_by(
+            &json!({ "client_id": "billing", "scope": "https://api.example.com/read" }),
+            None
+        ));
+        assert!(!rule.is_satisfied_by(
+            &json!({ "client_id": "reporting", "scope": "https://api.example.com/write" }),
+            None
+        ));
+        assert!(!rule.is_satisfied_by(&json!({ "client_id": "reporting" }), None));
+    }
+
+    #[test]
+    fn client_and_scope_rule_reads_the_audience_of_id_tokens() {
+        let rule = AuthorizationRule::ClientAndScope {
+            client_id: "reporting".to_string(),
+            scope: "openid".to_string(),
+        };
+
+        assert!(rule.is_satisfied_by(&json!({ "aud": "reporting", "scope": "openid" }), None));
+        assert!(rule.is_satisfied_by(
+            &json!({ "aud": ["billing", "reporting"], "scp": ["openid"] }),
+            None
+        ));
+    }
+}
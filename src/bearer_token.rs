@@ -0,0 +1,167 @@
+use http::{header, HeaderMap, HeaderName};
+
+/// Reason the bearer token of a request cannot be read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BearerTokenError {
+    /// The request has no header holding the token
+    Missing,
+    /// The header does not use the `Bearer` scheme, is not valid ASCII or the token contains
+    /// internal whitespace
+    Malformed,
+    /// The header holds no token after the scheme
+    Empty,
+}
+
+impl BearerTokenError {
+    /// Message describing the error, used as the body of responses rejecting the request
+    pub(crate) fn message(self) -> &'static str {
+        match self {
+            Self::Missing => "Missing 'Authorization' header",
+            Self::Malformed => "Malformed token",
+            Self::Empty => "Empty bearer token",
+        }
+    }
+}
+
+/// Read the bearer token of a request from its `Authorization` header, or the header named
+/// `header_name`
+///
+/// The scheme is matched case-insensitively, as HTTP authentication schemes are, and any other
+/// scheme such as `Basic` is rejected as malformed
+///
+/// Only depends on the headers of the request so it can be shared by the adapters of every
+/// framework
+pub(crate) fn bearer_token<'a>(
+    headers: &'a HeaderMap,
+    header_name: &HeaderName,
+) -> Result<&'a str, BearerTokenError> {
+    let header_value = headers.get(header_name).ok_or(BearerTokenError::Missing)?;
+    let raw_token = header_value
+        .to_str()
+        .map_err(|_| BearerTokenError::Malformed)?;
+
+    let token = match raw_token.get(.."Bearer ".len()) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("Bearer ") => &raw_token["Bearer ".len()..],
+        _ if raw_token.eq_ignore_ascii_case("Bearer") => "",
+        _ => return Err(BearerTokenError::Malformed),
+    };
+    check_token(token.trim())
+}
+
+/// Read the token of a request from the cookie named `cookie_name`
+///
+/// The cookie holds the token alone, without the `Bearer` scheme
+pub(crate) fn cookie_token<'a>(
+    headers: &'a HeaderMap,
+    cookie_name: &str,
+) -> Result<&'a str, BearerTokenError> {
+    let token = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|header_value| header_value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find_map(|(name, value)| (name == cookie_name).then_some(value))
+        .ok_or(BearerTokenError::Missing)?;
+    check_token(token.trim_matches('"'))
+}
+
+fn check_token(token: &str) -> Result<&str, BearerTokenError> {
+    if token.is_empty() {
+        return Err(BearerTokenError::Empty);
+    }
+
+    // A compact JWS never contains whitespace, a token with internal whitespace is corrupted or
+    // crafted
+    if token.contains(char::is_whitespace) {
+        return Err(BearerTokenError::Malformed);
+    }
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn headers_with_authorization(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn token_is_read_after_the_bearer_scheme() {
+        let headers = headers_with_authorization("Bearer a.b.c");
+
+        assert_eq!(bearer_token(&headers, &header::AUTHORIZATION), Ok("a.b.c"));
+    }
+
+    #[test]
+    fn bearer_scheme_is_matched_case_insensitively() {
+        for value in ["bearer a.b.c", "BEARER a.b.c"] {
+            let headers = headers_with_authorization(value);
+
+            assert_eq!(
+                bearer_token(&headers, &header::AUTHORIZATION),
+                Ok("a.b.c"),
+                "{value:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn header_of_another_scheme_is_malformed() {
+        for value in ["Basic YWxpY2U6c2VjcmV0", "Bearerx a.b.c", "a.b.c"] {
+            let headers = headers_with_authorization(value);
+
+            assert_eq!(
+                bearer_token(&headers, &header::AUTHORIZATION),
+                Err(BearerTokenError::Malformed),
+                "{value:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn bearer_scheme_without_a_token_is_empty() {
+        for value in ["Bearer", "Bearer ", "Bearer    "] {
+            let headers = headers_with_authorization(value);
+
+            assert_eq!(
+                bearer_token(&headers, &header::AUTHORIZATION),
+                Err(BearerTokenError::Empty),
+                "{value:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn request_without_the_header_is_missing_its_token() {
+        assert_eq!(
+            bearer_token(&HeaderMap::new(), &header::AUTHORIZATION),
+            Err(BearerTokenError::Missing)
+        );
+    }
+
+    #[test]
+    fn token_with_internal_whitespace_is_malformed() {
+        for value in ["Bearer a.b c", "Bearer a.b\tc", "Bearer  a.b.c extra"] {
+            let headers = headers_with_authorization(value);
+
+            assert_eq!(
+                bearer_token(&headers, &header::AUTHORIZATION),
+                Err(BearerTokenError::Malformed),
+                "{value:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_trimmed() {
+        let headers = headers_with_authorization("Bearer   a.b.c  ");
+
+        assert_eq!(bearer_token(&headers, &header::AUTHORIZATION), Ok("a.b.c"));
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::body::Body;
+use http::{HeaderMap, HeaderName, HeaderValue};
+use http_body::{Frame, SizeHint};
+use serde_json::Value;
+
+/// Claim of verified tokens sent as a response trailer, see
+/// `CognitoAuthLayer::with_claim_trailers`
+#[derive(Clone)]
+pub(crate) struct ClaimTrailer {
+    pub(crate) claim: String,
+    pub(crate) trailer_name: HeaderName,
+}
+
+/// Build the trailers holding the claims of a verified token
+///
+/// String claims are sent as-is and other claims as JSON, claims absent from the token or whose
+/// value is not a valid header value are left out
+pub(crate) fn claim_trailers(claim_trailers: &[ClaimTrailer], claims: &Value) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    for ClaimTrailer {
+        claim,
+        trailer_name,
+    } in claim_trailers
+    {
+        let header_value = match claims.get(claim) {
+            None | Some(Value::Null) => continue,
+            Some(Value::String(value)) => HeaderValue::from_str(value),
+            Some(value) => HeaderValue::from_str(&value.to_string()),
+        };
+        if let Ok(header_value) = header_value {
+            trailers.insert(trailer_name.clone(), header_value);
+        }
+    }
+    trailers
+}
+
+/// Response body sending trailers once the inner body is complete
+///
+/// Trailers sent by the inner body are merged with, and take precedence over, the added ones
+pub(crate) struct TrailersBody {
+    inner: Body,
+    trailers: Option<HeaderMap>,
+}
+
+impl TrailersBody {
+    pub(crate) fn new(inner: Body, trailers: HeaderMap) -> Self {
+        Self {
+            inner,
+            trailers: Some(trailers),
+        }
+    }
+}
+
+impl http_body::Body for TrailersBody {
+    type Data = <Body as http_body::Body>::Data;
+    type Error = <Body as http_body::Body>::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                let frame = match frame.into_trailers() {
+                    Ok(inner_trailers) => {
+                        let mut trailers = self.trailers.take().unwrap_or_default();
+                        trailers.extend(inner_trailers);
+                        Frame::trailers(trailers)
+                    }
+                    Err(frame) => frame,
+                };
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(None) => Poll::Ready(
+                self.trailers
+                    .take()
+                    .map(|trailers| Ok(Frame::trailers(trailers))),
+            ),
+            poll => poll,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.trailers.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        // An exact size would let the server send the body with a `Content-Length` rather than
+        // chunked, dropping the trailers
+        if self.trailers.is_some() {
+            SizeHint::default()
+        } else {
+            self.inner.size_hint()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, future::poll_fn, pin::pin};
+
+    use axum::{body::Bytes, extract::Request, routing::get, Router};
+    use futures_util::stream;
+    use http::header;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        test_support::{id_claims, id_validator, sign},
+        CognitoAuthLayer,
+    };
+
+    fn claim_trailer(claim: &str, trailer_name: &'static str) -> ClaimTrailer {
+        ClaimTrailer {
+            claim: claim.to_string(),
+            trailer_name: HeaderName::from_static(trailer_name),
+        }
+    }
+
+    /// Read a body to the end
+    ///
+    /// # Returns
+    /// the data of the body and its trailers, if any
+    async fn read_frames(
+        body: impl http_body::Body<Data = Bytes, Error = axum::Error>,
+    ) -> (String, HeaderMap) {
+        let mut body = pin!(body);
+        let mut data = Vec::new();
+        let mut trailers = HeaderMap::new();
+        while let Some(frame) = poll_fn(|cx| body.as_mut().poll_frame(cx)).await {
+            match frame.unwrap().into_data() {
+                Ok(chunk) => data.extend_from_slice(&chunk),
+                Err(frame) => trailers.extend(frame.into_trailers().unwrap()),
+            }
+        }
+        (String::from_utf8(data).unwrap(), trailers)
+    }
+
+    fn streaming_body() -> Body {
+        Body::from_stream(stream::iter(
+            ["first chunk, ", "second chunk"].map(Ok::<_, Infallible>),
+        ))
+    }
+
+    #[test]
+    fn string_claims_are_sent_as_is_and_other_claims_as_json() {
+        let trailers = claim_trailers(
+            &[
+                claim_trailer("cognito:username", "x-username"),
+                claim_trailer("cognito:groups", "x-groups"),
+                claim_trailer("email", "x-email"),
+            ],
+            &json!({ "cognito:username": "alice", "cognito:groups": ["admin", "users"] }),
+        );
+
+        assert_eq!(trailers["x-username"], "alice");
+        assert_eq!(trailers["x-groups"], r#"["admin","users"]"#);
+        assert!(!trailers.contains_key("x-email"));
+    }
+
+    #[tokio::test]
+    async fn trailers_are_sent_after_the_inner_body() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-username", HeaderValue::from_static("alice"));
+
+        let (data, sent_trailers) =
+            read_frames(TrailersBody::new(streaming_body(), trailers.clone())).await;
+
+        assert_eq!(data, "first chunk, second chunk");
+        assert_eq!(sent_trailers, trailers);
+    }
+
+    #[test]
+    fn body_of_a_known_size_has_no_exact_size_while_trailers_are_pending() {
+        let body = TrailersBody::new(Body::from("alice"), HeaderMap::new());
+
+        assert_eq!(http_body::Body::size_hint(&body).exact(), None);
+    }
+
+    #[tokio::test]
+    async fn claims_of_the_token_are_sent_as_trailers_of_a_streaming_response() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .with_claim_trailers(&[("cognito:username", "x-username"), ("sub", "x-subject")])
+            .unwrap();
+        let router = Router::new()
+            .route("/", get(|| async { streaming_body() }))
+            .layer(layer);
+        let claims = id_claims();
+        let request = Request::builder()
+            .uri("/")
+            .header(header::AUTHORIZATION, format!("Bearer {}", sign(&claims)))
+            .body(Body::empty())
+            .unwrap();
+
+        let response = router.oneshot(request).await.unwrap();
+
+        assert_eq!(response.headers()[header::TRAILER], "x-username, x-subject");
+        let (data, trailers) = read_frames(response.into_body()).await;
+        assert_eq!(data, "first chunk, second chunk");
+        assert_eq!(trailers["x-username"], "alice");
+        assert_eq!(trailers["x-subject"], claims["sub"].as_str().unwrap());
+    }
+}
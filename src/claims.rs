@@ -0,0 +1,69 @@
+use serde::{de, Deserialize, Deserializer};
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BoolOrString {
+    Bool(bool),
+    String(String),
+}
+
+/// Deserialize a boolean claim emitted either as a boolean or as the string `"true"` or `"false"`
+///
+/// Cognito and some Lambda triggers emit claims such as `email_verified` in either form, use it
+/// with `#[serde(deserialize_with = "axum_cognito::deserialize_bool_or_string")]` on a field of
+/// the user claims. Any other value fails deserialization.
+///
+/// # Errors
+/// Returns an error if the claim is neither a boolean nor one of the strings `"true"` and
+/// `"false"`
+pub fn deserialize_bool_or_string<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match BoolOrString::deserialize(deserializer)? {
+        BoolOrString::Bool(value) => Ok(value),
+        BoolOrString::String(value) => match value.as_str() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(de::Error::invalid_value(
+                de::Unexpected::Str(&value),
+                &"a boolean or the string \"true\" or \"false\"",
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct UserClaims {
+        #[serde(deserialize_with = "deserialize_bool_or_string")]
+        email_verified: bool,
+    }
+
+    fn email_verified(claims: serde_json::Value) -> Result<bool, serde_json::Error> {
+        serde_json::from_value::<UserClaims>(claims).map(|claims| claims.email_verified)
+    }
+
+    #[test]
+    fn booleans_and_stringified_booleans_are_accepted() {
+        assert!(email_verified(json!({ "email_verified": true })).unwrap());
+        assert!(!email_verified(json!({ "email_verified": false })).unwrap());
+        assert!(email_verified(json!({ "email_verified": "true" })).unwrap());
+        assert!(!email_verified(json!({ "email_verified": "false" })).unwrap());
+    }
+
+    #[test]
+    fn other_values_are_rejected() {
+        for value in [json!("True"), json!("yes"), json!(1), json!(null)] {
+            assert!(
+                email_verified(json!({ "email_verified": value })).is_err(),
+                "{value}"
+            );
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use ring::digest;
+use serde_json::Value;
+
+use crate::cognito_validator::unix_timestamp;
+
+struct CachedClaims {
+    claims: Value,
+    exp: u64,
+}
+
+/// Cache of the claims of verified tokens, until the tokens expire
+///
+/// Entries are keyed by the SHA-256 digest of the token so the tokens themselves are not kept
+/// in memory
+#[derive(Clone)]
+pub(crate) struct ClaimsCache {
+    entries: Arc<Mutex<HashMap<[u8; 32], CachedClaims>>>,
+    max_entries: usize,
+}
+
+impl ClaimsCache {
+    pub(crate) fn new(max_entries: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            max_entries,
+        }
+    }
+
+    /// Claims of a previously verified token, if it has not expired since
+    pub(crate) fn get(&self, token: &str) -> Option<Value> {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries
+            .get(&token_digest(token))
+            .filter(|cached| cached.exp > unix_timestamp())
+            .map(|cached| cached.claims.clone())
+    }
+
+    /// Cache the claims of a verified token until its `exp`, tokens without one are not cached
+    ///
+    /// When the cache is full expired entries are evicted, and the claims are not cached if that
+    /// does not free any space
+    pub(crate) fn insert(&self, token: &str, claims: &Value) {
+        let Some(exp) = claims.get("exp").and_then(Value::as_u64) else {
+            return;
+        };
+
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() >= self.max_entries {
+            let now = unix_timestamp();
+            entries.retain(|_, cached| cached.exp > now);
+            if entries.len() >= self.max_entries {
+                return;
+            }
+        }
+        entries.insert(
+            token_digest(token),
+            CachedClaims {
+                claims: claims.clone(),
+                exp,
+            },
+        );
+    }
+}
+
+fn token_digest(token: &str) -> [u8; 32] {
+    let mut token_digest = [0; 32];
+    token_digest.copy_from_slice(digest::digest(&digest::SHA256, token.as_bytes()).as_ref());
+    token_digest
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn claims_expiring_in(seconds: i64) -> Value {
+        json!({ "sub": "alice", "exp": unix_timestamp().saturating_add_signed(seconds) })
+    }
+
+    #[test]
+    fn claims_of_a_cached_token_are_returned_until_it_expires() {
+        let claims_cache = ClaimsCache::new(10);
+        let claims = claims_expiring_in(3600);
+        claims_cache.insert("live.token", &claims);
+        claims_cache.insert("expired.token", &claims_expiring_in(-1));
+
+        assert_eq!(claims_cache.get("live.token"), Some(claims));
+        assert_eq!(claims_cache.get("expired.token"), None);
+        assert_eq!(claims_cache.get("other.token"), None);
+    }
+
+    #[test]
+    fn tokens_without_exp_are_not_cached() {
+        let claims_cache = ClaimsCache::new(10);
+        claims_cache.insert("token", &json!({ "sub": "alice" }));
+
+        assert_eq!(claims_cache.get("token"), None);
+    }
+
+    #[test]
+    fn full_cache_evicts_expired_entries_only() {
+        let claims_cache = ClaimsCache::new(2);
+        claims_cache.insert("expired.token", &claims_expiring_in(-1));
+        claims_cache.insert("live.token", &claims_expiring_in(3600));
+
+        claims_cache.insert("new.token", &claims_expiring_in(3600));
+        claims_cache.insert("newer.token", &claims_expiring_in(3600));
+
+        assert!(claims_cache.get("live.token").is_some());
+        assert!(claims_cache.get("new.token").is_some());
+        assert_eq!(claims_cache.get("newer.token"), None);
+    }
+
+    #[test]
+    fn cache_is_keyed_by_the_digest_of_the_token() {
+        let claims_cache = ClaimsCache::new(10);
+        claims_cache.insert("token", &claims_expiring_in(3600));
+
+        let entries = claims_cache.entries.lock().unwrap();
+        assert!(entries.contains_key(&token_digest("token")));
+    }
+}
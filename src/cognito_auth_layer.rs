@@ -1,21 +1,197 @@
-use std::task::{Context, Poll};
+use std::{
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use axum::{body::Body, extract::Request, response::Response};
-use futures_util::future::BoxFuture;
-use http::StatusCode;
+use futures_util::{future::BoxFuture, FutureExt, TryFutureExt};
+use http::{header, Extensions, HeaderName, HeaderValue, Method, StatusCode};
 use tower::{Layer, Service};
 
-use crate::{AxumCognitoError, CognitoValidator, OAuthTokenType};
+#[cfg(feature = "opentelemetry")]
+use crate::telemetry::AuthorizeSpan;
+use crate::{
+    bearer_token::{bearer_token, cookie_token, BearerTokenError},
+    claim_trailers::{claim_trailers, ClaimTrailer, TrailersBody},
+    cognito_validator::unix_timestamp,
+    AuthorizationEngine, AuthorizationRule, AxumCognitoError, CognitoMultiPoolValidator,
+    CognitoValidator, CorrelationId, Decision, GroupNormalizer, Issuer, OAuthTokenType,
+    RequestContext, Scopes, Username, VerifiedToken,
+};
+
+/// Response header set when the client should refresh its token
+const REFRESH_RECOMMENDED_HEADER: &str = "X-Token-Refresh-Recommended";
+
+/// Realm of the `WWW-Authenticate` challenges when none is configured
+pub(crate) const DEFAULT_REALM: &str = "api";
+
+/// Error codes of the `WWW-Authenticate` challenges, as defined in RFC 6750
+const INVALID_REQUEST: &str = "invalid_request";
+pub(crate) const INVALID_TOKEN: &str = "invalid_token";
+const INSUFFICIENT_SCOPE: &str = "insufficient_scope";
+
+/// Validator(s) used by the layer to verify tokens
+#[derive(Clone)]
+enum ValidatorSource<UC>
+where
+    UC: for<'de> serde::Deserialize<'de>,
+{
+    Single(Arc<CognitoValidator<UC>>),
+    MultiPool(CognitoMultiPoolValidator<UC>),
+}
+
+impl<UC> ValidatorSource<UC>
+where
+    UC: for<'de> serde::Deserialize<'de>,
+{
+    /// Validator of a request, a multi-pool validator routing by issuer reads the token of the
+    /// request as the layer does, from its header or its cookie
+    fn select(
+        &self,
+        request: &Request,
+        options: &LayerOptions<UC>,
+    ) -> Option<Arc<CognitoValidator<UC>>> {
+        match self {
+            Self::Single(validator) => Some(validator.clone()),
+            Self::MultiPool(validator) => {
+                validator.resolve_with_token(request, request_token(request, options).ok())
+            }
+        }
+    }
+
+    /// Whether the pool of every validator has authorization rules, `false` when no pool is
+    /// registered yet as pools registered later may have none
+    fn every_pool_has_rules(&self) -> bool {
+        match self {
+            Self::Single(validator) => !validator.pool_config().rules().is_empty(),
+            Self::MultiPool(validator) => {
+                let validators = validator.validators();
+                !validators.is_empty()
+                    && validators
+                        .iter()
+                        .all(|validator| !validator.pool_config().rules().is_empty())
+            }
+        }
+    }
+}
+
+/// Issuer, token type, client ids and minimum RSA key size of the validator a `VerifiedToken` of
+/// the request extensions was verified by, so that the layers further down the stack only reuse it
+/// when their validator accepts the same signatures
+#[derive(Clone, PartialEq, Eq)]
+struct VerifiedBy {
+    issuer: String,
+    token_use: &'static str,
+    client_ids: Vec<String>,
+    min_rsa_bits: Option<usize>,
+}
+
+impl VerifiedBy {
+    fn new<UC>(validator: &CognitoValidator<UC>) -> Self
+    where
+        UC: for<'de> serde::Deserialize<'de>,
+    {
+        Self {
+            issuer: validator.issuer().to_string(),
+            token_use: validator.token_type().token_use(),
+            client_ids: validator.pool_config().client_ids().to_vec(),
+            min_rsa_bits: validator.min_rsa_bits(),
+        }
+    }
+}
+
+/// Enriches the claims of a verified token, see `CognitoAuthLayer::with_claims_enricher`
+type ClaimsEnricher<UC> =
+    Arc<dyn Fn(&UC) -> BoxFuture<'static, Result<Extensions, Response>> + Send + Sync>;
+
+/// Body of the responses of requests rejected by the authorization rules
+#[derive(Clone)]
+enum ForbiddenBody {
+    Text(&'static str),
+    Json(serde_json::Value),
+}
+
+/// Claim the correlation id of requests is read from and response header it is echoed in, see
+/// `CognitoAuthLayer::with_correlation_from_claim`
+#[derive(Clone)]
+struct Correlation {
+    claim: String,
+    header_name: HeaderName,
+}
+
+/// Options of the layer, shared with every middleware it creates
+#[derive(Clone)]
+#[allow(clippy::struct_excessive_bools)]
+struct LayerOptions<UC> {
+    refresh_hint: Option<Duration>,
+    rules: Vec<AuthorizationRule>,
+    group_normalizer: Option<GroupNormalizer>,
+    claims_enricher: Option<ClaimsEnricher<UC>>,
+    authorization_engine: Option<Arc<dyn AuthorizationEngine>>,
+    deny_by_default: bool,
+    realm: String,
+    forbidden_body: ForbiddenBody,
+    header_name: HeaderName,
+    cookie_name: Option<String>,
+    mark_sensitive: bool,
+    hide_as_not_found: bool,
+    reverify: bool,
+    catch_panic: bool,
+    missing_claim_status: StatusCode,
+    correlation: Option<Correlation>,
+    claim_trailers: Vec<ClaimTrailer>,
+    maintenance_mode: Option<Arc<AtomicBool>>,
+    #[cfg(feature = "opentelemetry")]
+    otel_subject: bool,
+}
+
+impl<UC> Default for LayerOptions<UC> {
+    fn default() -> Self {
+        Self {
+            refresh_hint: None,
+            rules: Vec::new(),
+            group_normalizer: None,
+            claims_enricher: None,
+            authorization_engine: None,
+            deny_by_default: false,
+            realm: DEFAULT_REALM.to_string(),
+            forbidden_body: ForbiddenBody::Text("Forbidden"),
+            header_name: header::AUTHORIZATION,
+            cookie_name: None,
+            mark_sensitive: false,
+            hide_as_not_found: false,
+            reverify: false,
+            catch_panic: false,
+            missing_claim_status: StatusCode::FORBIDDEN,
+            correlation: None,
+            claim_trailers: Vec::new(),
+            maintenance_mode: None,
+            #[cfg(feature = "opentelemetry")]
+            otel_subject: false,
+        }
+    }
+}
 
 /// Layer for authorising routes using AWS Cognito
 ///
 /// This layer uses the `Authorization` header. The haeder is decoded and the User Claims extracted
+/// into a `VerifiedToken` inserted into the request extensions
+///
+/// The request body is never read, buffered or replaced by this layer, it is passed through to the
+/// inner service untouched
 #[derive(Clone)]
 pub struct CognitoAuthLayer<UC>
 where
     UC: for<'de> serde::Deserialize<'de>,
 {
-    validator: CognitoValidator<UC>,
+    validator: ValidatorSource<UC>,
+    options: LayerOptions<UC>,
 }
 
 impl<UC> CognitoAuthLayer<UC>
@@ -25,7 +201,371 @@ where
     /// Create a layer directly from a validator
     #[must_use]
     pub fn from_validator(validator: CognitoValidator<UC>) -> Self {
-        Self { validator }
+        Self {
+            validator: ValidatorSource::Single(Arc::new(validator)),
+            options: LayerOptions::default(),
+        }
+    }
+
+    /// Create a layer verifying the token of each request against the pool of its tenant
+    #[must_use]
+    pub fn from_multi_pool_validator(validator: CognitoMultiPoolValidator<UC>) -> Self {
+        Self {
+            validator: ValidatorSource::MultiPool(validator),
+            options: LayerOptions::default(),
+        }
+    }
+
+    /// Authorization rules checked by the layer for every request
+    ///
+    /// Rules specific to a pool are available from the `PoolConfig` of its validator
+    #[must_use]
+    pub fn rules(&self) -> &[AuthorizationRule] {
+        &self.options.rules
+    }
+
+    /// Recommend clients to refresh their token when it is about to expire
+    ///
+    /// The `X-Token-Refresh-Recommended: true` header is added to the response when the verified
+    /// token expires within `threshold`
+    #[must_use]
+    pub fn with_refresh_hint(mut self, threshold: Duration) -> Self {
+        self.options.refresh_hint = Some(threshold);
+        self
+    }
+
+    /// Set the realm of the `WWW-Authenticate` challenges of the responses rejecting requests,
+    /// `api` by default
+    ///
+    /// The realm is set per layer so route groups sharing a validator can use different realms
+    #[must_use]
+    pub fn with_realm(mut self, realm: &str) -> Self {
+        self.options.realm = realm.to_string();
+        self
+    }
+
+    /// Read the bearer token from the header named `header_name` rather than `Authorization`,
+    /// e.g. when a proxy forwards the original `Authorization` header under another name
+    ///
+    /// The header still holds `Bearer <token>` and is looked up case-insensitively
+    ///
+    /// # Errors
+    /// Returns an `AxumCognitoError::InvalidConfiguration` if `header_name` is not a valid header
+    /// name
+    pub fn with_header_name<N>(mut self, header_name: N) -> Result<Self, AxumCognitoError>
+    where
+        N: TryInto<HeaderName>,
+        N::Error: std::fmt::Display,
+    {
+        self.options.header_name = header_name.try_into().map_err(|error| {
+            AxumCognitoError::InvalidConfiguration(format!("invalid header name: {error}"))
+        })?;
+        Ok(self)
+    }
+
+    /// Read the token from the cookie named `cookie_name` when the request has no token header
+    ///
+    /// The header takes precedence over the cookie, which holds the token alone without the
+    /// `Bearer` scheme
+    #[must_use]
+    pub fn with_cookie_name(mut self, cookie_name: &str) -> Self {
+        self.options.cookie_name = Some(cookie_name.to_string());
+        self
+    }
+
+    /// Set whether the values of every source the token is read from are marked sensitive on the
+    /// request forwarded to the inner service, disabled by default
+    ///
+    /// Both the token header and, when a cookie name is set, the `Cookie` header are marked,
+    /// whichever the token was read from, so later layers such as loggers do not print them
+    #[must_use]
+    pub fn with_mark_sensitive(mut self, mark_sensitive: bool) -> Self {
+        self.options.mark_sensitive = mark_sensitive;
+        self
+    }
+
+    /// Respond with a bare 404 instead of a 400, 401, 403 or the missing claim status when the
+    /// layer rejects a request, disabled by default
+    ///
+    /// Hides the existence of the routes behind the layer from unauthenticated clients, apply a
+    /// separate layer to the routes to hide. This deliberately trades the ability of clients to
+    /// tell why they were rejected for obscurity. Error responses of the claims enricher are
+    /// returned as-is, whatever their status.
+    #[must_use]
+    pub fn with_hide_as_not_found(mut self, hide_as_not_found: bool) -> Self {
+        self.options.hide_as_not_found = hide_as_not_found;
+        self
+    }
+
+    /// Set the body of the 403 responses of requests rejected by the authorization rules,
+    /// `Forbidden` by default
+    #[must_use]
+    pub fn with_forbidden_body(mut self, body_text: &'static str) -> Self {
+        self.options.forbidden_body = ForbiddenBody::Text(body_text);
+        self
+    }
+
+    /// Set a JSON body for the 403 responses of requests rejected by the authorization rules,
+    /// served as `application/json`
+    #[must_use]
+    pub fn with_forbidden_json(mut self, body: serde_json::Value) -> Self {
+        self.options.forbidden_body = ForbiddenBody::Json(body);
+        self
+    }
+
+    /// Set whether tokens already verified by another `CognitoAuthLayer` of the stack are
+    /// verified again, disabled by default
+    ///
+    /// When the layer is applied at both a router and a sub-router, the inner layer finds the
+    /// `VerifiedToken` inserted by the outer one and, provided it was verified against the same
+    /// issuer, token type, client ids and minimum RSA key size as its own validator, authorises it
+    /// with its own rules, pool rules, authorization engine and claims enricher rather than
+    /// verifying the token again. The checks of its own validator that follow the signature,
+    /// such as the maximum token age or the audience policy, are still run on the reused token.
+    #[must_use]
+    pub fn with_reverification(mut self, reverify: bool) -> Self {
+        self.options.reverify = reverify;
+        self
+    }
+
+    /// Set whether panics of the inner service are caught and answered with a bare 500, disabled
+    /// by default
+    ///
+    /// Panic handling is broadly the concern of the framework, this only makes sure a panicking
+    /// handler behind the layer does not tear down the connection ambiguously
+    #[must_use]
+    pub fn with_catch_panic(mut self, catch_panic: bool) -> Self {
+        self.options.catch_panic = catch_panic;
+        self
+    }
+
+    /// Set the status of the responses of requests rejected by a rule whose claim is entirely
+    /// absent from the token, e.g. a token without any `cognito:groups`, 403 by default
+    ///
+    /// Rules failing with their claim present always get a 403
+    ///
+    /// # Errors
+    /// Returns an `AxumCognitoError::InvalidConfiguration` if `missing_claim_status` is not a
+    /// client error status, e.g. a 200 that would let rejected requests pass as successful
+    pub fn with_missing_claim_status(
+        mut self,
+        missing_claim_status: StatusCode,
+    ) -> Result<Self, AxumCognitoError> {
+        if !missing_claim_status.is_client_error() {
+            return Err(AxumCognitoError::InvalidConfiguration(format!(
+                "missing claim status must be a client error status, got {missing_claim_status}"
+            )));
+        }
+        self.options.missing_claim_status = missing_claim_status;
+        Ok(self)
+    }
+
+    /// Derive the correlation id of requests from the `claim` of their verified token, e.g.
+    /// `jti`, and echo it in the `header_name` header of the response
+    ///
+    /// The id is inserted into the request extensions as a `CorrelationId`, requests whose token
+    /// has no such string or numeric claim get none
+    ///
+    /// # Errors
+    /// Returns an `AxumCognitoError::InvalidConfiguration` if `header_name` is not a valid header
+    /// name
+    pub fn with_correlation_from_claim<N>(
+        mut self,
+        claim: &str,
+        header_name: N,
+    ) -> Result<Self, AxumCognitoError>
+    where
+        N: TryInto<HeaderName>,
+        N::Error: std::fmt::Display,
+    {
+        let header_name = header_name.try_into().map_err(|error| {
+            AxumCognitoError::InvalidConfiguration(format!("invalid header name: {error}"))
+        })?;
+        self.options.correlation = Some(Correlation {
+            claim: claim.to_string(),
+            header_name,
+        });
+        Ok(self)
+    }
+
+    /// Send claims of the verified token as response trailers, rather than headers, once the
+    /// inner service's response body is complete
+    ///
+    /// Each `(claim, trailer_name)` pair sends the value of `claim` in the `trailer_name` trailer,
+    /// as-is for string claims and as JSON otherwise, and the trailers are announced by the
+    /// `Trailer` header. Meant for streaming responses, trailers only reach HTTP/2 clients and
+    /// HTTP/1.1 clients accepting them.
+    ///
+    /// # Errors
+    /// Returns an `AxumCognitoError::InvalidConfiguration` if a trailer name is not a valid header
+    /// name
+    pub fn with_claim_trailers(
+        mut self,
+        claim_trailers: &[(&str, &str)],
+    ) -> Result<Self, AxumCognitoError> {
+        for (claim, trailer_name) in claim_trailers {
+            let trailer_name = HeaderName::try_from(*trailer_name).map_err(|error| {
+                AxumCognitoError::InvalidConfiguration(format!("invalid trailer name: {error}"))
+            })?;
+            self.options.claim_trailers.push(ClaimTrailer {
+                claim: (*claim).to_string(),
+                trailer_name,
+            });
+        }
+        Ok(self)
+    }
+
+    /// Reject authorised write requests with a bare 503 while `maintenance_mode` is set, e.g.
+    /// during a migration
+    ///
+    /// The flag is read for every request so it can be toggled at runtime. Requests other than
+    /// `GET` and `HEAD` are rejected after their token is verified and the authorization rules
+    /// are checked, so the users attempting writes are logged, while reads proceed as usual.
+    #[must_use]
+    pub fn with_maintenance_mode(mut self, maintenance_mode: Arc<AtomicBool>) -> Self {
+        self.options.maintenance_mode = Some(maintenance_mode);
+        self
+    }
+
+    /// Only authorise tokens of users belonging to `group`
+    ///
+    /// Tokens whose `cognito:groups` claim does not list the group get a 403
+    #[must_use]
+    pub fn require_group(mut self, group: &str) -> Self {
+        self.options
+            .rules
+            .push(AuthorizationRule::Group(group.to_string()));
+        self
+    }
+
+    /// Only authorise tokens granted at least one scope of a resource server
+    ///
+    /// Cognito prefixes the custom scopes of a resource server with its identifier, e.g.
+    /// `https://api.example.com/read`, tokens only granted scopes of other resource servers get a
+    /// 403
+    #[must_use]
+    pub fn require_resource_server(mut self, identifier_prefix: &str) -> Self {
+        self.options.rules.push(AuthorizationRule::ResourceServer(
+            identifier_prefix.to_string(),
+        ));
+        self
+    }
+
+    /// Only authorise tokens issued to the client `client_id` and granted `scope`
+    ///
+    /// Both are checked together, tokens of other clients granted the scope, or of the client
+    /// without the scope, get a 403. Clearer than pairing an audience check with a separate
+    /// scope rule when a scope is only meaningful for a given client.
+    #[must_use]
+    pub fn require_client_and_scope(mut self, client_id: &str, scope: &str) -> Self {
+        self.options.rules.push(AuthorizationRule::ClientAndScope {
+            client_id: client_id.to_string(),
+            scope: scope.to_string(),
+        });
+        self
+    }
+
+    /// Normalize group names before checking group rules
+    ///
+    /// The normalizer is applied to both the required groups and the groups of the token, e.g.
+    /// `Arc::new(|group: &str| group.to_lowercase())` for case-insensitive matching
+    #[must_use]
+    pub fn with_group_normalizer(mut self, group_normalizer: GroupNormalizer) -> Self {
+        self.options.group_normalizer = Some(group_normalizer);
+        self
+    }
+
+    /// Enrich the claims of verified tokens before the request reaches the inner service
+    ///
+    /// The enricher is called once per request with the user claims, e.g. to load the roles of
+    /// the user from a database. Its output is inserted into the request extensions, while an
+    /// error response is returned to the client as-is without calling the inner service.
+    #[must_use]
+    pub fn with_claims_enricher<E, F, Fut>(mut self, claims_enricher: F) -> Self
+    where
+        E: Clone + Send + Sync + 'static,
+        F: Fn(&UC) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<E, Response>> + Send + 'static,
+    {
+        self.options.claims_enricher = Some(Arc::new(move |claims| {
+            claims_enricher(claims)
+                .map_ok(|enrichment| {
+                    let mut extensions = Extensions::new();
+                    extensions.insert(enrichment);
+                    extensions
+                })
+                .boxed()
+        }));
+        self
+    }
+
+    /// Delegate the decision to authorise requests to `authorization_engine`, e.g. a policy engine
+    ///
+    /// The engine is called once the token is verified and the authorization rules are satisfied,
+    /// with the raw claims of the token and the method, path and headers of the request. Requests
+    /// it denies get a 403.
+    #[must_use]
+    pub fn with_authorization_engine(
+        mut self,
+        authorization_engine: Arc<dyn AuthorizationEngine>,
+    ) -> Self {
+        self.options.authorization_engine = Some(authorization_engine);
+        self
+    }
+
+    /// Only authorise tokens whose `claim` holds a unix timestamp in the past
+    ///
+    /// Tokens failing the check, or without the claim, get a 403
+    #[must_use]
+    pub fn require_claim_time_passed(mut self, claim: &str) -> Self {
+        self.options
+            .rules
+            .push(AuthorizationRule::ClaimTimePassed(claim.to_string()));
+        self
+    }
+
+    /// Record the `sub` of verified tokens, as `enduser.id`, on the `cognito.authorize` span
+    ///
+    /// Disabled by default as the subject identifies the user
+    #[cfg(feature = "opentelemetry")]
+    #[must_use]
+    pub fn with_otel_subject(mut self) -> Self {
+        self.options.otel_subject = true;
+        self
+    }
+
+    /// Deny every request unless at least one authorization rule, or an authorization engine, is
+    /// configured
+    ///
+    /// Guards against deploying routes that authenticate users without authorizing them:
+    /// `build` fails when no rule is configured and, should the layer be used without calling
+    /// `build`, every request gets a 403.
+    #[must_use]
+    pub fn deny_by_default(mut self) -> Self {
+        self.options.deny_by_default = true;
+        self
+    }
+
+    /// Check the configuration of the layer
+    ///
+    /// # Returns
+    /// the layer, unchanged
+    ///
+    /// # Errors
+    /// Returns an `AxumCognitoError::InvalidConfiguration` if the layer denies by default and no
+    /// authorization rule is configured, either on the layer or on the pool of every validator,
+    /// and no authorization engine is configured
+    pub fn build(self) -> Result<Self, AxumCognitoError> {
+        if self.options.deny_by_default
+            && self.options.rules.is_empty()
+            && self.options.authorization_engine.is_none()
+            && !self.validator.every_pool_has_rules()
+        {
+            return Err(AxumCognitoError::InvalidConfiguration(
+                "deny by default requires at least one authorization rule".to_string(),
+            ));
+        }
+        Ok(self)
     }
 
     /// Create a layer
@@ -47,15 +587,15 @@ where
         cognito_pool_id: &str,
         cognito_region: &str,
     ) -> Result<Self, AxumCognitoError> {
-        Ok(Self {
-            validator: CognitoValidator::new(
+        Ok(Self::from_validator(
+            CognitoValidator::new(
                 token_type,
                 cognito_client_id,
                 cognito_pool_id,
                 cognito_region,
             )
             .await?,
-        })
+        ))
     }
 }
 
@@ -68,6 +608,7 @@ where
         CognitoAuthMiddleware {
             inner,
             validator: self.validator.clone(),
+            options: Arc::new(self.options.clone()),
         }
     }
 }
@@ -78,7 +619,8 @@ where
     UC: for<'de> serde::Deserialize<'de>,
 {
     inner: S,
-    validator: CognitoValidator<UC>,
+    validator: ValidatorSource<UC>,
+    options: Arc<LayerOptions<UC>>,
 }
 
 impl<S, UC> Service<Request> for CognitoAuthMiddleware<S, UC>
@@ -95,54 +637,1521 @@ where
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, request: Request) -> Self::Future {
-        let validator = self.validator.clone();
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        let validator = self.validator.select(&request, &self.options);
+        let options = self.options.clone();
 
         // see here for why and how to clone the inner service
         // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
         let clone = self.inner.clone();
         let mut inner = std::mem::replace(&mut self.inner, clone);
+        #[cfg(feature = "opentelemetry")]
+        let span = AuthorizeSpan::start(
+            validator
+                .as_ref()
+                .map(|validator| validator.token_type().token_use()),
+        );
         Box::pin(async move {
-            let (parts, body) = request.into_parts();
-            let headers = &parts.headers;
-
-            let Some(header_value) = headers.get("Authorization") else {
-                let response = create_bad_request_response("Missing 'Authorization' header");
-                return Ok(response);
+            let authorization = authorize(validator, &options, &mut request).await;
+            #[cfg(feature = "opentelemetry")]
+            span.end::<UC>(
+                authorization.as_ref().err().map(Rejection::status),
+                &request,
+                options.otel_subject,
+            );
+            let refresh_recommended = match authorization {
+                Ok(refresh_recommended) => refresh_recommended,
+                Err(rejection) => return Ok(rejection.into_response(options.hide_as_not_found)),
             };
+            if is_write_in_maintenance(&request, &options) {
+                tracing::info!(
+                    username = request
+                        .extensions()
+                        .get::<Username>()
+                        .map(|username| username.0.as_str()),
+                    method = %request.method(),
+                    "Rejected a write request during maintenance"
+                );
+                return Ok(create_service_unavailable_response());
+            }
+            let correlation_header = options.correlation.as_ref().and_then(|correlation| {
+                let correlation_id = request.extensions().get::<CorrelationId>()?;
+                let header_value = HeaderValue::from_str(&correlation_id.0).ok()?;
+                Some((correlation.header_name.clone(), header_value))
+            });
+            let trailers = request
+                .extensions()
+                .get::<VerifiedToken<UC>>()
+                .map(|verified_token| claim_trailers(&options.claim_trailers, &verified_token.raw))
+                .filter(|trailers| !trailers.is_empty());
 
-            let Ok(raw_token) = header_value.to_str() else {
-                let response = create_bad_request_response("Malformed token");
-                return Ok(response);
+            let mut response = if options.catch_panic {
+                // The call itself is inside the caught future so that panics raised before the
+                // inner service returns its future are caught too
+                let call = AssertUnwindSafe(async move { inner.call(request).await });
+                if let Ok(response) = call.catch_unwind().await {
+                    response?
+                } else {
+                    tracing::error!("Inner service panicked");
+                    create_internal_server_error_response()
+                }
+            } else {
+                inner.call(request).await?
             };
+            if refresh_recommended {
+                response
+                    .headers_mut()
+                    .insert(REFRESH_RECOMMENDED_HEADER, HeaderValue::from_static("true"));
+            }
+            if let Some((header_name, header_value)) = correlation_header {
+                response.headers_mut().insert(header_name, header_value);
+            }
+            if let Some(trailers) = trailers {
+                let trailer_names = trailers.keys().map(HeaderName::as_str).collect::<Vec<_>>();
+                if let Ok(trailer_names) = HeaderValue::from_str(&trailer_names.join(", ")) {
+                    response
+                        .headers_mut()
+                        .insert(header::TRAILER, trailer_names);
+                }
+                response = response.map(|body| Body::new(TrailersBody::new(body, trailers)));
+            }
+            Ok(response)
+        })
+    }
+}
 
-            let token = raw_token["Bearer ".len()..].trim_start();
+/// Response of a request rejected by `authorize`
+enum Rejection {
+    /// Authentication or authorization failure of the layer, hidden as a 404 when the layer
+    /// hides the routes behind it
+    Failure(Response),
+    /// Error response of the claims enricher, always returned as-is
+    Enricher(Response),
+}
 
-            let Ok(some_claims) = validator.validate_token(token).await else {
-                let response = create_bad_request_response("Missing 'Authorization' header");
-                return Ok(response);
-            };
+impl Rejection {
+    #[cfg(feature = "opentelemetry")]
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::Failure(response) | Self::Enricher(response) => response.status(),
+        }
+    }
 
-            let Some(user_claims) = some_claims else {
-                let mut response = Response::default();
-                *response.status_mut() = StatusCode::UNAUTHORIZED;
-                return Ok(response);
-            };
+    fn into_response(self, hide_as_not_found: bool) -> Response {
+        match self {
+            Self::Failure(_) if hide_as_not_found => create_not_found_response(),
+            Self::Failure(response) | Self::Enricher(response) => response,
+        }
+    }
+}
 
-            let mut request = Request::from_parts(parts, body);
+/// Verify the token of a request and check the authorization rules, inserting the verified token
+/// into the request extensions
+///
+/// # Returns
+/// whether the client should be recommended to refresh its token, or the rejection of the
+/// request when it is not authorised
+async fn authorize<UC>(
+    validator: Option<Arc<CognitoValidator<UC>>>,
+    options: &LayerOptions<UC>,
+    request: &mut Request,
+) -> Result<bool, Rejection>
+where
+    UC: for<'de> serde::Deserialize<'de> + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    let Some(validator) = validator else {
+        return Err(Rejection::Failure(create_unauthorized_response(
+            &options.realm,
+            None,
+        )));
+    };
 
-            let extensions = request.extensions_mut();
-            extensions.insert(user_claims);
+    let verified_token = match reusable_verified_token(&validator, options, request) {
+        Some(verified_token) => verified_token,
+        None => verify_request_token(&validator, options, request)
+            .await
+            .map_err(Rejection::Failure)?,
+    };
 
-            let response = inner.call(request).await?;
-            Ok(response)
-        })
+    let pool_rules = validator.pool_config().rules();
+    if options.deny_by_default
+        && options.rules.is_empty()
+        && options.authorization_engine.is_none()
+        && pool_rules.is_empty()
+    {
+        return Err(Rejection::Failure(create_forbidden_response(options)));
+    }
+    if let Some(response) = check_rules(
+        options.rules.iter().chain(pool_rules),
+        &verified_token.raw,
+        options,
+    ) {
+        return Err(Rejection::Failure(response));
+    }
+    if let Some(authorization_engine) = &options.authorization_engine {
+        let request_context = RequestContext {
+            method: request.method().clone(),
+            path: request.uri().path().to_string(),
+            headers: request.headers().clone(),
+        };
+        if !is_allowed_by_engine(
+            authorization_engine.as_ref(),
+            &verified_token.raw,
+            &request_context,
+        )
+        .await
+        {
+            return Err(Rejection::Failure(create_forbidden_response(options)));
+        }
+    }
+
+    let refresh_recommended = options
+        .refresh_hint
+        .is_some_and(|threshold| expires_within(verified_token.exp, threshold));
+
+    let enrichment = match &options.claims_enricher {
+        Some(claims_enricher) => Some(
+            claims_enricher(&verified_token.claims)
+                .await
+                .map_err(Rejection::Enricher)?,
+        ),
+        None => None,
+    };
+
+    insert_verified_token(request, options, &validator, verified_token);
+    if let Some(enrichment) = enrichment {
+        request.extensions_mut().extend(enrichment);
+    }
+
+    Ok(refresh_recommended)
+}
+
+/// Verified token inserted by another layer of the stack, when the layer does not verify tokens
+/// again, it was verified against the same issuer, token type, client ids and minimum RSA key size
+/// as `validator` and it passes the checks of `validator` that follow the signature
+fn reusable_verified_token<UC>(
+    validator: &CognitoValidator<UC>,
+    options: &LayerOptions<UC>,
+    request: &Request,
+) -> Option<VerifiedToken<UC>>
+where
+    UC: for<'de> serde::Deserialize<'de> + Clone + Send + Sync + 'static,
+{
+    if options.reverify {
+        return None;
+    }
+    let verified_by = request.extensions().get::<VerifiedBy>()?;
+    if *verified_by != VerifiedBy::new(validator) {
+        return None;
+    }
+    let verified_token = request.extensions().get::<VerifiedToken<UC>>()?;
+    let token = request_token(request, options).ok()?;
+    validator
+        .accepts_verified_claims(token, &verified_token.raw)
+        .then(|| verified_token.clone())
+}
+
+/// Read the token of a request from the configured header, or from the cookie when the header is
+/// missing
+fn request_token<'a, UC>(
+    request: &'a Request,
+    options: &LayerOptions<UC>,
+) -> Result<&'a str, BearerTokenError> {
+    // The header takes precedence over the cookie
+    match (
+        bearer_token(request.headers(), &options.header_name),
+        &options.cookie_name,
+    ) {
+        (Err(BearerTokenError::Missing), Some(cookie_name)) => {
+            cookie_token(request.headers(), cookie_name)
+        }
+        (token, _) => token,
+    }
+}
+
+/// Read the token of a request and verify it
+///
+/// # Returns
+/// the verified token, or the response to return when the token is missing or invalid
+async fn verify_request_token<UC>(
+    validator: &CognitoValidator<UC>,
+    options: &LayerOptions<UC>,
+    request: &mut Request,
+) -> Result<VerifiedToken<UC>, Response>
+where
+    UC: for<'de> serde::Deserialize<'de> + Clone + Send + Sync + 'static,
+{
+    // Only the headers and extensions are touched, the body is forwarded to the inner
+    // service as-is so streaming uploads are never buffered or polled by this layer
+    if options.mark_sensitive {
+        mark_token_sources_sensitive(request, options);
+    }
+
+    let token = match request_token(request, options) {
+        Ok(token) => token,
+        Err(error) => {
+            // Requests without any credentials get no error code, as per RFC 6750
+            let challenge_error = (error != BearerTokenError::Missing).then_some(INVALID_REQUEST);
+            let response =
+                create_bad_request_response(error.message(), &options.realm, challenge_error);
+            return Err(response);
+        }
+    };
+
+    let Ok(some_verified_token) = validator.verify_token(token).await else {
+        let response = create_bad_request_response(
+            "Missing 'Authorization' header",
+            &options.realm,
+            Some(INVALID_REQUEST),
+        );
+        return Err(response);
+    };
+
+    some_verified_token
+        .ok_or_else(|| create_unauthorized_response(&options.realm, Some(INVALID_TOKEN)))
+}
+
+/// Insert a verified token into the request extensions, along with the context, scopes, username,
+/// issuer and correlation id derived from its claims and the `VerifiedBy` marker of `validator`
+fn insert_verified_token<UC>(
+    request: &mut Request,
+    options: &LayerOptions<UC>,
+    validator: &CognitoValidator<UC>,
+    verified_token: VerifiedToken<UC>,
+) where
+    UC: for<'de> serde::Deserialize<'de> + Clone + Send + Sync + 'static,
+{
+    let auth_context = validator.auth_context(&verified_token.raw);
+    let scopes = Scopes::from_claims(&verified_token.raw);
+    let username = Username::from_claims(&verified_token.raw);
+    let issuer = Issuer::from_claims(&verified_token.raw);
+    let correlation_id = options
+        .correlation
+        .as_ref()
+        .and_then(|correlation| CorrelationId::from_claim(&verified_token.raw, &correlation.claim));
+
+    let extensions = request.extensions_mut();
+    extensions.insert(verified_token);
+    extensions.insert(VerifiedBy::new(validator));
+    extensions.insert(auth_context);
+    extensions.insert(scopes);
+    if let Some(username) = username {
+        extensions.insert(username);
+    }
+    if let Some(issuer) = issuer {
+        extensions.insert(issuer);
+    }
+    if let Some(correlation_id) = correlation_id {
+        extensions.insert(correlation_id);
+    }
+}
+
+/// Ask the authorization engine whether the request is authorised, logging the reason of denials
+async fn is_allowed_by_engine(
+    authorization_engine: &dyn AuthorizationEngine,
+    claims: &serde_json::Value,
+    request_context: &RequestContext,
+) -> bool {
+    match authorization_engine
+        .authorize(claims, request_context)
+        .await
+    {
+        Decision::Allow => true,
+        Decision::Deny(reason) => {
+            tracing::debug!("Request denied by the authorization engine: {reason}");
+            false
+        }
     }
 }
 
-fn create_bad_request_response(body_text: &'static str) -> Response {
+/// Mark the values of every header the token can be read from as sensitive
+fn mark_token_sources_sensitive<UC>(request: &mut Request, options: &LayerOptions<UC>) {
+    let headers = request.headers_mut();
+    let mut header_names = vec![options.header_name.clone()];
+    if options.cookie_name.is_some() {
+        header_names.push(header::COOKIE);
+    }
+    for header_name in header_names {
+        if let header::Entry::Occupied(mut entry) = headers.entry(header_name) {
+            for header_value in entry.iter_mut() {
+                header_value.set_sensitive(true);
+            }
+        }
+    }
+}
+
+/// Check the claims of a verified token against authorization rules
+///
+/// # Returns
+/// the response rejecting the request if a rule fails, with the missing claim status when the
+/// claim of the rule is entirely absent and a 403 otherwise
+fn check_rules<'a, UC>(
+    mut rules: impl Iterator<Item = &'a AuthorizationRule>,
+    claims: &serde_json::Value,
+    options: &LayerOptions<UC>,
+) -> Option<Response> {
+    let failed_rule =
+        rules.find(|rule| !rule.is_satisfied_by(claims, options.group_normalizer.as_ref()))?;
+
+    if !failed_rule.is_claim_missing(claims) {
+        return Some(create_forbidden_response(options));
+    }
+    Some(match options.missing_claim_status {
+        StatusCode::FORBIDDEN => create_forbidden_response(options),
+        StatusCode::UNAUTHORIZED => {
+            create_unauthorized_response(&options.realm, Some(INVALID_TOKEN))
+        }
+        status => {
+            let mut response = Response::default();
+            *response.status_mut() = status;
+            response
+        }
+    })
+}
+
+fn expires_within(exp: i64, threshold: Duration) -> bool {
+    let remaining_lifetime = u64::try_from(exp)
+        .unwrap_or_default()
+        .saturating_sub(unix_timestamp());
+    remaining_lifetime < threshold.as_secs()
+}
+
+/// Build the `Bearer` challenge of the `WWW-Authenticate` header of a response, as described in
+/// RFC 6750
+///
+/// Quotes and backslashes in the realm are escaped, `None` is returned if the realm still isn't a
+/// valid header value
+fn challenge(realm: &str, error: Option<&str>) -> Option<HeaderValue> {
+    let realm = realm.replace('\\', "\\\\").replace('"', "\\\"");
+    let challenge = match error {
+        Some(error) => format!("Bearer realm=\"{realm}\", error=\"{error}\""),
+        None => format!("Bearer realm=\"{realm}\""),
+    };
+    HeaderValue::from_str(&challenge).ok()
+}
+
+fn with_challenge(mut response: Response, realm: &str, error: Option<&str>) -> Response {
+    if let Some(challenge) = challenge(realm, error) {
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, challenge);
+    }
+    response
+}
+
+/// Whether a request is a write request while the maintenance mode is enabled
+fn is_write_in_maintenance<UC>(request: &Request, options: &LayerOptions<UC>) -> bool {
+    options
+        .maintenance_mode
+        .as_ref()
+        .is_some_and(|maintenance_mode| maintenance_mode.load(Ordering::Relaxed))
+        && !matches!(*request.method(), Method::GET | Method::HEAD)
+}
+
+fn create_internal_server_error_response() -> Response {
+    let mut response = Response::default();
+    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    response
+}
+
+fn create_service_unavailable_response() -> Response {
+    let mut response = Response::default();
+    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    response
+}
+
+fn create_not_found_response() -> Response {
+    let mut response = Response::default();
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
+
+fn create_bad_request_response(
+    body_text: &'static str,
+    realm: &str,
+    error: Option<&str>,
+) -> Response {
     let mut response = Response::default();
     *response.status_mut() = StatusCode::BAD_REQUEST;
     *response.body_mut() = Body::from(body_text);
-    response
+    with_challenge(response, realm, error)
+}
+
+pub(crate) fn create_unauthorized_response(realm: &str, error: Option<&str>) -> Response {
+    let mut response = Response::default();
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+    with_challenge(response, realm, error)
+}
+
+/// Build the response of every request rejected by the authorization rules
+fn create_forbidden_response<UC>(options: &LayerOptions<UC>) -> Response {
+    let mut response = Response::default();
+    *response.status_mut() = StatusCode::FORBIDDEN;
+    match &options.forbidden_body {
+        ForbiddenBody::Text(body_text) => *response.body_mut() = Body::from(*body_text),
+        ForbiddenBody::Json(body) => {
+            *response.body_mut() = Body::from(body.to_string());
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+        }
+    }
+    with_challenge(response, &options.realm, Some(INSUFFICIENT_SCOPE))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::atomic::AtomicUsize};
+
+    use axum::{
+        routing::{get, post},
+        Extension, Router,
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        test_support::{
+            access_claims, access_validator, id_claims, id_validator, jwks, pool_config,
+            rotated_jwks, sign, validator_with, MockJwks, CLIENT_ID, POOL_ID, REGION,
+        },
+        AuthContext, PoolConfig,
+    };
+
+    async fn ok() -> &'static str {
+        "ok"
+    }
+
+    /// Request to `/` carrying `token` as a bearer token
+    fn request_with_token(token: &str) -> Request {
+        Request::builder()
+            .uri("/")
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// Validator of the test pool that cannot verify any test token, as its JWKS only publishes
+    /// the rotated key
+    async fn rotated_validator(pool_config: PoolConfig) -> CognitoValidator<Value> {
+        validator_with(
+            OAuthTokenType::Id,
+            pool_config,
+            MockJwks::new(rotated_jwks()),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn inner_layer_reuses_a_token_verified_for_the_same_pool() {
+        let inner = CognitoAuthLayer::from_validator(rotated_validator(pool_config()).await);
+        let outer = CognitoAuthLayer::from_validator(id_validator().await);
+        let router = Router::new().route("/", get(ok)).layer(inner).layer(outer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn inner_layer_verifies_again_a_token_verified_for_other_clients() {
+        let inner = CognitoAuthLayer::from_validator(
+            validator_with(
+                OAuthTokenType::Id,
+                PoolConfig::new(&["other-client"], POOL_ID, REGION),
+                MockJwks::new(jwks()),
+            )
+            .await,
+        );
+        let outer = CognitoAuthLayer::from_validator(id_validator().await);
+        let router = Router::new().route("/", get(ok)).layer(inner).layer(outer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn inner_layer_checks_its_pool_rules_on_a_reused_token() {
+        let inner = CognitoAuthLayer::from_validator(
+            rotated_validator(pool_config().with_required_groups(&["admin"])).await,
+        );
+        let outer = CognitoAuthLayer::from_validator(id_validator().await);
+        let router = Router::new().route("/", get(ok)).layer(inner).layer(outer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn inner_layer_checks_its_own_validator_options_on_a_reused_token() {
+        let inner = CognitoAuthLayer::from_validator(
+            id_validator()
+                .await
+                .with_max_token_age(Duration::from_secs(60)),
+        );
+        let outer = CognitoAuthLayer::from_validator(id_validator().await);
+        let router = Router::new().route("/", get(ok)).layer(inner).layer(outer);
+        let mut claims = id_claims();
+        claims["iat"] = json!(unix_timestamp() - 600);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&claims)))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn inner_layer_verifies_again_a_token_verified_with_a_smaller_minimum_key_size() {
+        let inner = CognitoAuthLayer::from_validator(
+            rotated_validator(pool_config())
+                .await
+                .with_min_rsa_bits(2048),
+        );
+        let outer = CognitoAuthLayer::from_validator(id_validator().await);
+        let router = Router::new().route("/", get(ok)).layer(inner).layer(outer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn inner_layer_denies_a_reused_token_by_default_without_rules() {
+        let inner = CognitoAuthLayer::from_validator(rotated_validator(pool_config()).await)
+            .deny_by_default();
+        let outer = CognitoAuthLayer::from_validator(id_validator().await);
+        let router = Router::new().route("/", get(ok)).layer(inner).layer(outer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn inner_layer_enriches_a_reused_token() {
+        async fn enriched(Extension(role): Extension<String>) -> String {
+            role
+        }
+        let inner = CognitoAuthLayer::from_validator(rotated_validator(pool_config()).await)
+            .with_claims_enricher(|_claims: &Value| async { Ok("editor".to_string()) });
+        let outer = CognitoAuthLayer::from_validator(id_validator().await);
+        let router = Router::new()
+            .route("/", get(enriched))
+            .layer(inner)
+            .layer(outer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "editor");
+    }
+
+    #[tokio::test]
+    async fn deny_by_default_build_fails_without_rules() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await).deny_by_default();
+
+        assert!(matches!(
+            layer.build(),
+            Err(AxumCognitoError::InvalidConfiguration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn deny_by_default_build_succeeds_with_a_rule() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .deny_by_default()
+            .require_group("admin");
+
+        assert!(layer.build().is_ok());
+    }
+
+    #[tokio::test]
+    async fn deny_by_default_build_fails_without_any_registered_pool() {
+        let validator = CognitoMultiPoolValidator::<Value>::new(HashMap::new(), |_| None);
+        let layer = CognitoAuthLayer::from_multi_pool_validator(validator).deny_by_default();
+
+        assert!(matches!(
+            layer.build(),
+            Err(AxumCognitoError::InvalidConfiguration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn deny_by_default_rejects_every_request_without_rules() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await).deny_by_default();
+        let router = Router::new().route("/", get(ok)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn hidden_layer_answers_rejections_with_a_404() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .with_hide_as_not_found(true)
+            .require_group("admin");
+        let router = Router::new().route("/", get(ok)).layer(layer);
+
+        let invalid_token = router
+            .clone()
+            .oneshot(request_with_token("not-a-token"))
+            .await
+            .unwrap();
+        let missing_group = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(invalid_token.status(), StatusCode::NOT_FOUND);
+        assert_eq!(missing_group.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn hidden_layer_answers_a_custom_missing_claim_status_with_a_404() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .with_hide_as_not_found(true)
+            .with_missing_claim_status(StatusCode::UNPROCESSABLE_ENTITY)
+            .unwrap()
+            .require_group("admin");
+        let router = Router::new().route("/", get(ok)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn hidden_layer_returns_enricher_errors_as_is() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .with_hide_as_not_found(true)
+            .with_claims_enricher(|_claims: &Value| async {
+                let mut response = Response::default();
+                *response.status_mut() = StatusCode::FORBIDDEN;
+                Err::<(), _>(response)
+            });
+        let router = Router::new().route("/", get(ok)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn large_streamed_body_reaches_the_inner_service_intact() {
+        async fn echo(request: Request) -> Body {
+            request.into_body()
+        }
+        let chunks = (0..64u8).map(|chunk| Ok::<_, std::io::Error>(vec![chunk; 64 * 1024]));
+        let expected = chunks.clone().flat_map(Result::unwrap).collect::<Vec<_>>();
+        let layer = CognitoAuthLayer::from_validator(id_validator().await);
+        let router = Router::new().route("/", post(echo)).layer(layer);
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(
+                header::AUTHORIZATION,
+                format!("Bearer {}", sign(&id_claims())),
+            )
+            .body(Body::from_stream(futures_util::stream::iter(chunks)))
+            .unwrap();
+
+        let response = router.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, expected);
+    }
+
+    #[tokio::test]
+    async fn refresh_is_recommended_for_a_token_about_to_expire() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .with_refresh_hint(Duration::from_secs(300));
+        let router = Router::new().route("/", get(ok)).layer(layer);
+        let mut expiring_claims = id_claims();
+        expiring_claims["exp"] = json!(unix_timestamp() + 60);
+
+        let expiring = router
+            .clone()
+            .oneshot(request_with_token(&sign(&expiring_claims)))
+            .await
+            .unwrap();
+        let fresh = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(expiring.headers()[REFRESH_RECOMMENDED_HEADER], "true");
+        assert!(!fresh.headers().contains_key(REFRESH_RECOMMENDED_HEADER));
+    }
+
+    #[tokio::test]
+    async fn empty_bearer_token_gets_a_400() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await);
+        let router = Router::new().route("/", get(ok)).layer(layer);
+        let request = Request::builder()
+            .uri("/")
+            .header(header::AUTHORIZATION, "Bearer  ")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = router.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "Empty bearer token");
+    }
+
+    #[tokio::test]
+    async fn bearer_token_with_internal_whitespace_gets_a_400() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await);
+        let router = Router::new().route("/", get(ok)).layer(layer);
+        let request = Request::builder()
+            .uri("/")
+            .header(header::AUTHORIZATION, "Bearer a.b c")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = router.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "Malformed token");
+    }
+
+    #[tokio::test]
+    async fn token_activated_in_the_future_gets_a_403() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .require_claim_time_passed("activate_at");
+        let router = Router::new().route("/", get(ok)).layer(layer);
+        let mut claims = id_claims();
+        claims["activate_at"] = json!(unix_timestamp() + 3600);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&claims)))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn required_groups_of_the_pool_of_the_tenant_are_checked() {
+        let validators = HashMap::from([
+            ("acme".to_string(), id_validator().await),
+            (
+                "globex".to_string(),
+                validator_with(
+                    OAuthTokenType::Id,
+                    pool_config().with_required_groups(&["admin"]),
+                    MockJwks::new(jwks()),
+                )
+                .await,
+            ),
+        ]);
+        let validator = CognitoMultiPoolValidator::new(validators, |request| {
+            request
+                .headers()
+                .get("x-tenant")
+                .and_then(|tenant| tenant.to_str().ok())
+                .map(str::to_string)
+        });
+        let layer = CognitoAuthLayer::from_multi_pool_validator(validator);
+        let router = Router::new().route("/", get(ok)).layer(layer);
+        let request_of_tenant = |tenant: &'static str| {
+            let mut request = request_with_token(&sign(&id_claims()));
+            request
+                .headers_mut()
+                .insert("x-tenant", HeaderValue::from_static(tenant));
+            request
+        };
+
+        let acme = router
+            .clone()
+            .oneshot(request_of_tenant("acme"))
+            .await
+            .unwrap();
+        let globex = router.oneshot(request_of_tenant("globex")).await.unwrap();
+
+        assert_eq!(acme.status(), StatusCode::OK);
+        assert_eq!(globex.status(), StatusCode::FORBIDDEN);
+    }
+
+    async fn body_text(response: Response) -> String {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn auth_context_of_the_pool_and_client_is_inserted() {
+        async fn context(auth_context: AuthContext) -> String {
+            format!(
+                "{} {} {}",
+                auth_context.region, auth_context.pool_id, auth_context.client_id
+            )
+        }
+        let layer = CognitoAuthLayer::from_validator(access_validator().await);
+        let router = Router::new().route("/", get(context)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&access_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            body_text(response).await,
+            format!("{REGION} {POOL_ID} {CLIENT_ID}")
+        );
+    }
+
+    #[tokio::test]
+    async fn rules_of_the_layer_are_listed_in_order() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .require_group("admin")
+            .require_resource_server("https://api.example.com");
+
+        assert_eq!(
+            layer.rules(),
+            [
+                AuthorizationRule::Group("admin".to_string()),
+                AuthorizationRule::ResourceServer("https://api.example.com".to_string()),
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(layer.rules()).unwrap(),
+            json!([
+                { "rule": "group", "value": "admin" },
+                { "rule": "resource_server", "value": "https://api.example.com" },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn group_rule_matches_normalized_groups() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .require_group("Admins")
+            .with_group_normalizer(Arc::new(|group: &str| group.to_lowercase()));
+        let router = Router::new().route("/", get(ok)).layer(layer);
+        let mut claims = id_claims();
+        claims["cognito:groups"] = json!(["admins"]);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&claims)))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn enrichment_of_the_claims_reaches_the_handler() {
+        #[derive(Clone)]
+        struct Roles(Vec<String>);
+        async fn roles(Extension(roles): Extension<Roles>) -> String {
+            roles.0.join(",")
+        }
+        let layer = CognitoAuthLayer::from_validator(id_validator().await).with_claims_enricher(
+            |claims: &Value| {
+                let owner = format!("owner-of-{}", claims["cognito:username"].as_str().unwrap());
+                async move { Ok(Roles(vec!["reader".to_string(), owner])) }
+            },
+        );
+        let router = Router::new().route("/", get(roles)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(body_text(response).await, "reader,owner-of-alice");
+    }
+
+    #[tokio::test]
+    async fn enricher_error_is_returned_without_calling_the_handler() {
+        async fn unreachable() -> &'static str {
+            panic!("the handler must not be called")
+        }
+        let layer = CognitoAuthLayer::from_validator(id_validator().await).with_claims_enricher(
+            |_claims: &Value| async {
+                let mut response = Response::new(Body::from("roles unavailable"));
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                Err::<(), _>(response)
+            },
+        );
+        let router = Router::new().route("/", get(unreachable)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body_text(response).await, "roles unavailable");
+    }
+
+    #[tokio::test]
+    async fn scopes_of_the_verified_token_are_inserted() {
+        async fn scopes(scopes: Scopes) -> String {
+            scopes.contains("openid").to_string()
+        }
+        let layer = CognitoAuthLayer::from_validator(access_validator().await);
+        let router = Router::new().route("/", get(scopes)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&access_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(body_text(response).await, "true");
+    }
+
+    #[tokio::test]
+    async fn challenges_use_the_realm_of_the_layer() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .with_realm("admin \"api\"")
+            .require_group("admin");
+        let router = Router::new().route("/", get(ok)).layer(layer);
+        let mut expired_claims = id_claims();
+        expired_claims["exp"] = json!(unix_timestamp() - 60);
+
+        let missing = router
+            .clone()
+            .oneshot(Request::new(Body::empty()))
+            .await
+            .unwrap();
+        let expired = router
+            .clone()
+            .oneshot(request_with_token(&sign(&expired_claims)))
+            .await
+            .unwrap();
+        let forbidden = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            missing.headers()[header::WWW_AUTHENTICATE],
+            r#"Bearer realm="admin \"api\"""#
+        );
+        assert_eq!(
+            expired.headers()[header::WWW_AUTHENTICATE],
+            r#"Bearer realm="admin \"api\"", error="invalid_token""#
+        );
+        assert_eq!(
+            forbidden.headers()[header::WWW_AUTHENTICATE],
+            r#"Bearer realm="admin \"api\"", error="insufficient_scope""#
+        );
+    }
+
+    #[tokio::test]
+    async fn username_of_the_verified_token_is_inserted() {
+        async fn username(Username(username): Username) -> String {
+            username
+        }
+        let layer = CognitoAuthLayer::from_validator(access_validator().await);
+        let router = Router::new().route("/", get(username)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&access_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(body_text(response).await, "alice");
+    }
+
+    #[tokio::test]
+    async fn token_is_read_from_the_configured_header() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .with_header_name("x-forwarded-authorization")
+            .unwrap();
+        let router = Router::new().route("/", get(ok)).layer(layer);
+        let token = sign(&id_claims());
+
+        let forwarded = router
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header("X-Forwarded-Authorization", format!("Bearer {token}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let authorization = router.oneshot(request_with_token(&token)).await.unwrap();
+
+        assert_eq!(forwarded.status(), StatusCode::OK);
+        assert_eq!(authorization.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn invalid_header_name_is_an_invalid_configuration() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await);
+
+        assert!(matches!(
+            layer.with_header_name("x forwarded"),
+            Err(AxumCognitoError::InvalidConfiguration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rule_failures_get_the_configured_forbidden_body() {
+        let group = CognitoAuthLayer::from_validator(id_validator().await)
+            .require_group("admin")
+            .with_forbidden_body("Admins only");
+        let scope = CognitoAuthLayer::from_validator(access_validator().await)
+            .require_resource_server("https://api.example.com")
+            .with_forbidden_body("Admins only");
+
+        let group = Router::new()
+            .route("/", get(ok))
+            .layer(group)
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+        let scope = Router::new()
+            .route("/", get(ok))
+            .layer(scope)
+            .oneshot(request_with_token(&sign(&access_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(group.status(), StatusCode::FORBIDDEN);
+        assert_eq!(body_text(group).await, "Admins only");
+        assert_eq!(scope.status(), StatusCode::FORBIDDEN);
+        assert_eq!(body_text(scope).await, "Admins only");
+    }
+
+    #[tokio::test]
+    async fn forbidden_body_defaults_to_forbidden() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await).require_group("admin");
+        let router = Router::new().route("/", get(ok)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(body_text(response).await, "Forbidden");
+    }
+
+    #[tokio::test]
+    async fn forbidden_json_body_is_served_as_json() {
+        let body = json!({ "error": "forbidden", "required_group": "admin" });
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .require_group("admin")
+            .with_forbidden_json(body.clone());
+        let router = Router::new().route("/", get(ok)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(
+            serde_json::from_str::<Value>(&body_text(response).await).unwrap(),
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn panic_of_the_handler_is_caught_as_a_500() {
+        async fn panicking() -> &'static str {
+            panic!("handler bug")
+        }
+        let layer = CognitoAuthLayer::from_validator(id_validator().await).with_catch_panic(true);
+        let router = Router::new().route("/", get(panicking)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    /// Status of the responses of the layer for a token without any group and one of other groups
+    async fn missing_and_mismatched_group_statuses(
+        missing_claim_status: Option<StatusCode>,
+    ) -> (StatusCode, StatusCode) {
+        let mut layer =
+            CognitoAuthLayer::from_validator(id_validator().await).require_group("admin");
+        if let Some(missing_claim_status) = missing_claim_status {
+            layer = layer
+                .with_missing_claim_status(missing_claim_status)
+                .unwrap();
+        }
+        let router = Router::new().route("/", get(ok)).layer(layer);
+        let mut claims = id_claims();
+        claims["cognito:groups"] = json!(["users"]);
+
+        let missing = router
+            .clone()
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+        let mismatched = router
+            .oneshot(request_with_token(&sign(&claims)))
+            .await
+            .unwrap();
+        (missing.status(), mismatched.status())
+    }
+
+    #[tokio::test]
+    async fn missing_claim_gets_a_403_by_default() {
+        assert_eq!(
+            missing_and_mismatched_group_statuses(None).await,
+            (StatusCode::FORBIDDEN, StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_claim_gets_the_configured_status() {
+        assert_eq!(
+            missing_and_mismatched_group_statuses(Some(StatusCode::UNAUTHORIZED)).await,
+            (StatusCode::UNAUTHORIZED, StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            missing_and_mismatched_group_statuses(Some(StatusCode::UNPROCESSABLE_ENTITY)).await,
+            (StatusCode::UNPROCESSABLE_ENTITY, StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_claim_status_other_than_a_client_error_is_rejected() {
+        for status in [
+            StatusCode::OK,
+            StatusCode::FOUND,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ] {
+            let layer = CognitoAuthLayer::from_validator(id_validator().await)
+                .with_missing_claim_status(status);
+
+            assert!(
+                matches!(layer, Err(AxumCognitoError::InvalidConfiguration(_))),
+                "{status}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn issuer_of_the_verified_token_is_inserted() {
+        async fn issuer(Issuer(issuer): Issuer) -> String {
+            issuer
+        }
+        let layer = CognitoAuthLayer::from_validator(id_validator().await);
+        let router = Router::new().route("/", get(issuer)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert_eq!(body_text(response).await, crate::test_support::issuer());
+    }
+
+    /// Request to `/` carrying `token` in the `session` cookie, along with an `Authorization`
+    /// header when `authorization` is set
+    fn request_with_cookie(token: &str, authorization: Option<&str>) -> Request {
+        let mut request = Request::builder()
+            .uri("/")
+            .header(header::COOKIE, format!("theme=dark; session={token}"));
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn token_is_read_from_the_cookie_without_a_token_header() {
+        let layer =
+            CognitoAuthLayer::from_validator(id_validator().await).with_cookie_name("session");
+        let router = Router::new().route("/", get(ok)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_cookie(&sign(&id_claims()), None))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn token_header_takes_precedence_over_the_cookie() {
+        let layer =
+            CognitoAuthLayer::from_validator(id_validator().await).with_cookie_name("session");
+        let router = Router::new().route("/", get(ok)).layer(layer);
+        let mut expired_claims = id_claims();
+        expired_claims["exp"] = json!(unix_timestamp() - 60);
+
+        let response = router
+            .oneshot(request_with_cookie(
+                &sign(&id_claims()),
+                Some(&format!("Bearer {}", sign(&expired_claims))),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn token_read_from_the_cookie_is_routed_by_issuer() {
+        let validator = CognitoMultiPoolValidator::from_issuers(vec![id_validator().await]);
+        let layer =
+            CognitoAuthLayer::from_multi_pool_validator(validator).with_cookie_name("session");
+        let router = Router::new().route("/", get(ok)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_cookie(&sign(&id_claims()), None))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn every_token_source_is_marked_sensitive() {
+        async fn sensitive(headers: http::HeaderMap) -> String {
+            format!(
+                "{} {}",
+                headers[header::AUTHORIZATION].is_sensitive(),
+                headers[header::COOKIE].is_sensitive()
+            )
+        }
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .with_cookie_name("session")
+            .with_mark_sensitive(true);
+        let router = Router::new().route("/", get(sensitive)).layer(layer);
+        let token = sign(&id_claims());
+
+        let response = router
+            .oneshot(request_with_cookie(
+                &token,
+                Some(&format!("Bearer {token}")),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(body_text(response).await, "true true");
+    }
+
+    #[tokio::test]
+    async fn correlation_id_of_the_token_reaches_the_handler_and_the_response() {
+        async fn correlation(CorrelationId(correlation_id): CorrelationId) -> String {
+            correlation_id
+        }
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .with_correlation_from_claim("jti", "X-Correlation-Id")
+            .unwrap();
+        let router = Router::new().route("/", get(correlation)).layer(layer);
+        let mut claims = id_claims();
+        claims["jti"] = json!("4f1a7c2e-jti");
+
+        let response = router
+            .oneshot(request_with_token(&sign(&claims)))
+            .await
+            .unwrap();
+
+        assert_eq!(response.headers()["x-correlation-id"], "4f1a7c2e-jti");
+        assert_eq!(body_text(response).await, "4f1a7c2e-jti");
+    }
+
+    #[tokio::test]
+    async fn token_without_the_correlation_claim_gets_no_correlation_id() {
+        async fn correlation(correlation_id: Option<Extension<CorrelationId>>) -> String {
+            correlation_id.is_some().to_string()
+        }
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .with_correlation_from_claim("jti", "X-Correlation-Id")
+            .unwrap();
+        let router = Router::new().route("/", get(correlation)).layer(layer);
+
+        let response = router
+            .oneshot(request_with_token(&sign(&id_claims())))
+            .await
+            .unwrap();
+
+        assert!(!response.headers().contains_key("x-correlation-id"));
+        assert_eq!(body_text(response).await, "false");
+    }
+
+    /// Allows requests of `alice` outside of `/admin` carrying an `x-tenant` header, counting its
+    /// calls
+    #[derive(Default)]
+    struct TenantPolicy {
+        calls: AtomicUsize,
+    }
+
+    #[axum::async_trait]
+    impl AuthorizationEngine for TenantPolicy {
+        async fn authorize(&self, claims: &Value, request: &RequestContext) -> Decision {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if claims["cognito:username"] != "alice" || request.method != Method::GET {
+                return Decision::Deny("not alice reading".to_string());
+            }
+            if request.path.starts_with("/admin") || !request.headers.contains_key("x-tenant") {
+                return Decision::Deny("outside of a tenant".to_string());
+            }
+            Decision::Allow
+        }
+    }
+
+    /// Status of a `GET` request to `path` with a valid token, with an `x-tenant` header when
+    /// `tenant` is set
+    async fn engine_status(layer: CognitoAuthLayer<Value>, path: &str, tenant: bool) -> StatusCode {
+        let router = Router::new()
+            .route("/", get(ok))
+            .route("/admin", get(ok))
+            .layer(layer);
+        let mut request = Request::builder().uri(path).header(
+            header::AUTHORIZATION,
+            format!("Bearer {}", sign(&id_claims())),
+        );
+        if tenant {
+            request = request.header("x-tenant", "acme");
+        }
+        router
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn authorization_engine_decides_on_the_claims_and_the_request() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .with_authorization_engine(Arc::new(TenantPolicy::default()));
+
+        assert_eq!(
+            engine_status(layer.clone(), "/", true).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            engine_status(layer.clone(), "/admin", true).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            engine_status(layer, "/", false).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn authorization_engine_is_not_called_when_a_rule_fails() {
+        let engine = Arc::new(TenantPolicy::default());
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .require_group("admin")
+            .with_authorization_engine(engine.clone());
+
+        assert_eq!(engine_status(layer, "/", true).await, StatusCode::FORBIDDEN);
+        assert_eq!(engine.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn authorization_engine_satisfies_deny_by_default() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .deny_by_default()
+            .with_authorization_engine(Arc::new(TenantPolicy::default()))
+            .build()
+            .unwrap();
+
+        assert_eq!(engine_status(layer, "/", true).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn writes_are_rejected_while_in_maintenance() {
+        let maintenance_mode = Arc::new(AtomicBool::new(true));
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .with_maintenance_mode(maintenance_mode.clone());
+        let router = Router::new().route("/", get(ok).post(ok)).layer(layer);
+        let token = sign(&id_claims());
+        let post = || {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let read = router
+            .clone()
+            .oneshot(request_with_token(&token))
+            .await
+            .unwrap();
+        let write = router.clone().oneshot(post()).await.unwrap();
+        maintenance_mode.store(false, Ordering::Relaxed);
+        let write_after_maintenance = router.oneshot(post()).await.unwrap();
+
+        assert_eq!(read.status(), StatusCode::OK);
+        assert_eq!(write.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(write_after_maintenance.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_writes_are_rejected_before_the_maintenance_check() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await)
+            .with_maintenance_mode(Arc::new(AtomicBool::new(true)));
+        let router = Router::new().route("/", post(ok)).layer(layer);
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
 }
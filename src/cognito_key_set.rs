@@ -0,0 +1,1008 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, PoisonError, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures_util::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use jsonwebtokens as jwt;
+use jwt::{Algorithm, AlgorithmID};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    cognito_validator::{numeric_timestamp, unix_timestamp},
+    AxumCognitoError, JwksFetcher,
+};
+
+/// UTF-8 byte order mark, some JWKS mirrors prefix the JSON document with it
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Standard claims holding unix timestamps
+const TIMESTAMP_CLAIMS: [&str; 4] = ["exp", "iat", "nbf", "auth_time"];
+
+/// Default minimum time between two fetches of the JWKS triggered by verification failures
+const DEFAULT_MIN_FETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum time between two fetches of the JWKS triggered by verification failures while fetches
+/// keep failing, unless the minimum fetch interval is longer
+const MAX_FETCH_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+/// Default time keys removed from the JWKS are still accepted
+const DEFAULT_ROTATION_GRACE: Duration = Duration::from_secs(5 * 60);
+
+/// Default maximum size, in bytes, of the JWKS response body
+const DEFAULT_MAX_JWKS_SIZE: usize = 256 * 1024;
+
+/// Maximum time to connect to the Cognito endpoint
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum time of a whole request to the Cognito endpoint, until its body is read
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fetch of the JWKS shared by every task waiting for it, the error is kept as a string as the
+/// output of a shared future has to be `Clone`
+type SharedFetch = Shared<BoxFuture<'static, Result<(), String>>>;
+
+/// Fetch of the JWKS in flight, along with its sequence number
+struct InFlightFetch {
+    sequence: u64,
+    fetch: SharedFetch,
+}
+
+/// Key of a JWKS, a JWKS may publish keys of other types next to the RSA keys, e.g. EC keys, so
+/// every field but `kty` is optional
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+struct CachedKey {
+    algorithm: Algorithm,
+    /// Size of the modulus of the RSA key, `None` for locally provided keys which are trusted
+    /// regardless of their size
+    modulus_bits: Option<usize>,
+    /// Whether the key was provided locally rather than fetched from the JWKS
+    local: bool,
+}
+
+struct Cache {
+    last_fetch: Option<Instant>,
+    /// Time the last fetch, successful or not, completed
+    last_attempt: Option<Instant>,
+    /// Number of fetches that failed since the last successful one
+    failed_fetches: u32,
+    keys: HashMap<String, Arc<CachedKey>>,
+    /// Key ids of the keys of the last JWKS fetched
+    fetched_kids: HashSet<String>,
+    /// Sequence number of the fetch the cached keys come from
+    fetch_sequence: u64,
+    /// Time after which keys removed from the JWKS are no longer accepted
+    retiring: HashMap<String, Instant>,
+}
+
+/// Public keys of a Cognito user pool, fetched from the pool's JWKS endpoint
+#[derive(Clone)]
+pub(crate) struct CognitoKeySet {
+    pub(crate) jwks_url: String,
+    issuer: String,
+    cache: Arc<RwLock<Cache>>,
+    in_flight_fetch: Arc<Mutex<Option<InFlightFetch>>>,
+    /// Sequence number of the last fetch started, so that a fetch completing after a fetch
+    /// started later does not replace its keys
+    fetch_sequence: Arc<AtomicU64>,
+    min_fetch_interval: Duration,
+    min_rsa_bits: Option<usize>,
+    rotation_grace: Duration,
+    max_jwks_size: usize,
+    fetcher: Option<Arc<dyn JwksFetcher>>,
+}
+
+impl CognitoKeySet {
+    /// Create a key set for a Cognito pool, no keys are fetched until `fetch` is called
+    ///
+    /// # Arguments
+    /// * `endpoint` - base url of the Cognito endpoint, e.g.
+    ///   `https://cognito-idp.eu-west-1.amazonaws.com`
+    /// * `cognito_pool_id` - pool id for the Cognito pool
+    pub(crate) fn new(endpoint: &str, cognito_pool_id: &str) -> Self {
+        let issuer = format!("{}/{cognito_pool_id}", endpoint.trim_end_matches('/'));
+        Self {
+            jwks_url: format!("{issuer}/.well-known/jwks.json"),
+            issuer,
+            cache: Arc::new(RwLock::new(Cache {
+                last_fetch: None,
+                last_attempt: None,
+                failed_fetches: 0,
+                keys: HashMap::new(),
+                fetched_kids: HashSet::new(),
+                fetch_sequence: 0,
+                retiring: HashMap::new(),
+            })),
+            in_flight_fetch: Arc::new(Mutex::new(None)),
+            fetch_sequence: Arc::new(AtomicU64::new(0)),
+            min_fetch_interval: DEFAULT_MIN_FETCH_INTERVAL,
+            min_rsa_bits: None,
+            rotation_grace: DEFAULT_ROTATION_GRACE,
+            max_jwks_size: DEFAULT_MAX_JWKS_SIZE,
+            fetcher: None,
+        }
+    }
+
+    /// Set the minimum time between two fetches of the JWKS triggered by verification failures
+    pub(crate) fn set_min_fetch_interval(&mut self, min_fetch_interval: Duration) {
+        self.min_fetch_interval = min_fetch_interval;
+    }
+
+    /// Set the minimum size, in bits, of the modulus of the RSA keys tokens can be signed with
+    pub(crate) fn set_min_rsa_bits(&mut self, min_rsa_bits: usize) {
+        self.min_rsa_bits = Some(min_rsa_bits);
+    }
+
+    /// Minimum size, in bits, of the modulus of the RSA keys tokens can be signed with
+    pub(crate) fn min_rsa_bits(&self) -> Option<usize> {
+        self.min_rsa_bits
+    }
+
+    /// Fetch the JWKS using `fetcher` rather than from the pool's JWKS endpoint
+    pub(crate) fn set_fetcher(&mut self, fetcher: Arc<dyn JwksFetcher>) {
+        self.fetcher = Some(fetcher);
+    }
+
+    /// Set the time keys removed from the JWKS are still accepted
+    pub(crate) fn set_rotation_grace(&mut self, rotation_grace: Duration) {
+        self.rotation_grace = rotation_grace;
+    }
+
+    /// Set the maximum size, in bytes, of the response bodies fetched from the pool's endpoint,
+    /// its JWKS and its discovery document
+    pub(crate) fn set_max_jwks_size(&mut self, max_jwks_size: usize) {
+        self.max_jwks_size = max_jwks_size;
+    }
+
+    /// Time since the JWKS was last fetched successfully, `None` if it was never fetched
+    pub(crate) fn age(&self) -> Option<Duration> {
+        let cache = self.cache.read().unwrap_or_else(PoisonError::into_inner);
+        cache.last_fetch.map(|last_fetch| last_fetch.elapsed())
+    }
+
+    /// Issuer of the tokens signed by this key set
+    pub(crate) fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// Whether the key `kid` still verifies tokens, i.e. it was not removed from the JWKS or its
+    /// rotation grace period has not elapsed yet
+    pub(crate) fn has_key(&self, kid: &str) -> bool {
+        self.cached_key(kid).is_some()
+    }
+
+    /// Number of usable keys in the last JWKS fetched
+    pub(crate) fn fetched_key_count(&self) -> usize {
+        let cache = self.cache.read().unwrap_or_else(PoisonError::into_inner);
+        cache.fetched_kids.len()
+    }
+
+    /// Check that the issuer is reachable and its OIDC discovery document names it as the issuer
+    ///
+    /// # Errors
+    /// Returns an `AxumCognitoError::InvalidConfiguration` if the discovery document cannot be
+    /// fetched or names another issuer
+    pub(crate) async fn check_issuer(&self) -> Result<(), AxumCognitoError> {
+        let discovery_url = format!("{}/.well-known/openid-configuration", self.issuer);
+        let body = self.fetch_body(&discovery_url).await.map_err(|error| {
+            AxumCognitoError::InvalidConfiguration(format!(
+                "issuer `{}` is not reachable, check the pool id and region: {error}",
+                self.issuer
+            ))
+        })?;
+        let discovery: Value = serde_json::from_slice(&body).map_err(|error| {
+            AxumCognitoError::InvalidConfiguration(format!(
+                "discovery document of `{}` is not valid JSON: {error}",
+                self.issuer
+            ))
+        })?;
+        match discovery.get("issuer").and_then(Value::as_str) {
+            Some(issuer) if issuer == self.issuer => Ok(()),
+            issuer => Err(AxumCognitoError::InvalidConfiguration(format!(
+                "discovery document of `{}` names the issuer {issuer:?}",
+                self.issuer
+            ))),
+        }
+    }
+
+    /// Fetch the JWKS and cache its keys
+    ///
+    /// The JWKS is fetched by the custom fetcher if there is one, otherwise from the pool's JWKS
+    /// endpoint. The response is then parsed regardless of its content-type and a leading UTF-8
+    /// byte order mark is ignored.
+    ///
+    /// Keys no longer in the JWKS are still accepted during the rotation grace period, then
+    /// dropped. Locally provided keys are never dropped.
+    ///
+    /// # Errors
+    /// Returns an error if the JWKS cannot be fetched or parsed, or its response body is larger
+    /// than the maximum JWKS size
+    pub(crate) async fn fetch(&self) -> Result<(), AxumCognitoError> {
+        self.fetch_as(self.next_fetch_sequence()).await
+    }
+
+    fn next_fetch_sequence(&self) -> u64 {
+        self.fetch_sequence.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    async fn fetch_as(&self, sequence: u64) -> Result<(), AxumCognitoError> {
+        let jwk_set = match self.fetch_jwk_set().await {
+            Ok(jwk_set) => jwk_set,
+            Err(error) => {
+                // Failed fetches are throttled too, so that an unavailable JWKS endpoint is not
+                // fetched again on every verification of an unknown key
+                let mut cache = self.cache.write().unwrap_or_else(PoisonError::into_inner);
+                cache.last_attempt = Some(Instant::now());
+                cache.failed_fetches = cache.failed_fetches.saturating_add(1);
+                return Err(error);
+            }
+        };
+
+        let keys: HashMap<_, _> = jwk_set.keys.into_iter().filter_map(rsa_key).collect();
+
+        let now = Instant::now();
+        let mut cache = self.cache.write().unwrap_or_else(PoisonError::into_inner);
+        cache.last_attempt = Some(now);
+        cache.failed_fetches = 0;
+        if sequence < cache.fetch_sequence {
+            // A fetch started later, e.g. following a notified rotation, already replaced the keys
+            return Ok(());
+        }
+        cache.fetch_sequence = sequence;
+        cache.last_fetch = Some(now);
+        cache.fetched_kids = keys.keys().cloned().collect();
+
+        let Cache {
+            keys: cached_keys,
+            retiring,
+            ..
+        } = &mut *cache;
+        for kid in keys.keys() {
+            retiring.remove(kid);
+        }
+        retiring.retain(|kid, retire_at| {
+            let retired = *retire_at <= now;
+            if retired {
+                cached_keys.remove(kid);
+            }
+            !retired
+        });
+        for (kid, key) in cached_keys.iter() {
+            if !key.local && !keys.contains_key(kid) {
+                retiring
+                    .entry(kid.clone())
+                    .or_insert(now + self.rotation_grace);
+            }
+        }
+        cached_keys.extend(keys);
+        Ok(())
+    }
+
+    /// Read the response body of a document of the pool, e.g. its JWKS, aborting as soon as it
+    /// exceeds the maximum JWKS size rather than buffering an arbitrarily large body
+    async fn fetch_body(&self, url: &str) -> Result<Vec<u8>, AxumCognitoError> {
+        let mut response = http_client()?.get(url).send().await?.error_for_status()?;
+        let too_large = || {
+            AxumCognitoError::KeySet(format!(
+                "response body of `{url}` is larger than the maximum of {} bytes",
+                self.max_jwks_size
+            ))
+        };
+        if response
+            .content_length()
+            .is_some_and(|content_length| content_length > self.max_jwks_size as u64)
+        {
+            return Err(too_large());
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > self.max_jwks_size {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    async fn fetch_jwk_set(&self) -> Result<JwkSet, AxumCognitoError> {
+        if let Some(fetcher) = &self.fetcher {
+            Ok(serde_json::from_value(fetcher.fetch().await?)?)
+        } else {
+            let body = self.fetch_body(&self.jwks_url).await?;
+            Ok(parse_jwk_set(&body)?)
+        }
+    }
+
+    /// Fetch the JWKS straight away following a key rotation notified out-of-band
+    ///
+    /// A new fetch is always started, regardless of the minimum fetch interval and of any fetch
+    /// already in flight which may have started before the rotation. Verifications needing a
+    /// fetch join it rather than the earlier one.
+    ///
+    /// # Errors
+    /// Returns an error if the JWKS cannot be fetched or parsed
+    pub(crate) async fn rotate(&self) -> Result<(), AxumCognitoError> {
+        let fetch = {
+            let mut in_flight_fetch = self
+                .in_flight_fetch
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            self.start_fetch(&mut in_flight_fetch)
+        };
+        fetch.await.map_err(AxumCognitoError::KeySet)
+    }
+
+    /// Add a locally provided public key, e.g. to verify test tokens or in air-gapped setups
+    ///
+    /// The key is kept when the JWKS is fetched again, unless the JWKS holds a key with the same
+    /// key id
+    ///
+    /// # Errors
+    /// Returns an error if the algorithm is not an RSA or ECDSA algorithm or the key cannot be
+    /// parsed
+    pub(crate) fn add_local_key(
+        &self,
+        pem: &[u8],
+        kid: &str,
+        alg: &str,
+    ) -> Result<(), AxumCognitoError> {
+        let algorithm_id = AlgorithmID::from_str(alg)?;
+        let mut algorithm = match algorithm_id {
+            AlgorithmID::RS256
+            | AlgorithmID::RS384
+            | AlgorithmID::RS512
+            | AlgorithmID::PS256
+            | AlgorithmID::PS384
+            | AlgorithmID::PS512 => Algorithm::new_rsa_pem_verifier(algorithm_id, pem)?,
+            AlgorithmID::ES256 | AlgorithmID::ES384 => {
+                Algorithm::new_ecdsa_pem_verifier(algorithm_id, pem)?
+            }
+            _ => {
+                return Err(AxumCognitoError::KeySet(format!(
+                    "Unsupported algorithm `{alg}` for local public key `{kid}`"
+                )))
+            }
+        };
+        algorithm.set_kid(kid);
+
+        let mut cache = self.cache.write().unwrap_or_else(PoisonError::into_inner);
+        cache.keys.insert(
+            kid.to_string(),
+            Arc::new(CachedKey {
+                algorithm,
+                modulus_bits: None,
+                local: true,
+            }),
+        );
+        Ok(())
+    }
+
+    /// Verify the signature of a token and its claims
+    ///
+    /// The JWKS is fetched again when the token is signed by an unknown key or its signature does
+    /// not match the cached key, at most once every minimum fetch interval, backing off while
+    /// fetches keep failing. Any other verification failure is returned straight away.
+    ///
+    /// # Errors
+    /// Returns an error if the signing key cannot be found, is smaller than the minimum RSA key
+    /// size or the token fails verification
+    pub(crate) async fn verify(
+        &self,
+        token: &str,
+        verifier: &jwt::Verifier,
+    ) -> Result<Value, AxumCognitoError> {
+        let header = jwt::raw::decode_header_only(token)?;
+        let Some(Value::String(kid)) = header.get("kid") else {
+            return Err(
+                jwt::error::Error::MalformedToken(jwt::error::ErrorDetails::new(
+                    "Token has no 'kid'",
+                ))
+                .into(),
+            );
+        };
+
+        let (key, fetched) = if let Some(key) = self.cached_key(kid) {
+            (key, false)
+        } else {
+            if !self.can_fetch() {
+                return Err(AxumCognitoError::UnknownKeyId(kid.clone()));
+            }
+            (self.fetch_key(kid).await?, true)
+        };
+
+        match self.verify_with_key(token, verifier, kid, &key) {
+            // The key may have been rotated without changing its key id, retry once with the
+            // refreshed key set
+            Err(AxumCognitoError::Jsonwebtokens(jwt::error::Error::InvalidSignature()))
+                if !fetched && self.can_fetch() =>
+            {
+                let key = self.fetch_key(kid).await?;
+                self.verify_with_key(token, verifier, kid, &key)
+            }
+            verification => verification,
+        }
+    }
+
+    fn verify_with_key(
+        &self,
+        token: &str,
+        verifier: &jwt::Verifier,
+        kid: &str,
+        key: &CachedKey,
+    ) -> Result<Value, AxumCognitoError> {
+        if self
+            .min_rsa_bits
+            .zip(key.modulus_bits)
+            .is_some_and(|(min_rsa_bits, modulus_bits)| modulus_bits < min_rsa_bits)
+        {
+            return Err(AxumCognitoError::KeyTooSmall(kid.to_string()));
+        }
+
+        // The signature and the claims are verified separately so that timestamps emitted as
+        // floating-point numbers by non-standard issuers can be truncated to seconds first
+        let jwt::raw::TokenSlices {
+            message,
+            signature,
+            header,
+            claims,
+        } = jwt::raw::split_token(token)?;
+        let header = jwt::raw::decode_json_token_slice(header)?;
+        jwt::raw::verify_signature_only(&header, message, signature, &key.algorithm)?;
+        let mut claims = jwt::raw::decode_json_token_slice(claims)?;
+        truncate_timestamps(&mut claims);
+        verifier.verify_claims_only(&claims, unix_timestamp())?;
+        Ok(claims)
+    }
+
+    async fn fetch_key(&self, kid: &str) -> Result<Arc<CachedKey>, AxumCognitoError> {
+        self.fetch_once().await?;
+        self.cached_key(kid)
+            .ok_or_else(|| AxumCognitoError::UnknownKeyId(kid.to_string()))
+    }
+
+    /// Fetch the JWKS, joining the fetch already in flight if there is one
+    ///
+    /// Concurrent verifications of tokens signed by a new key all wait for a single fetch rather
+    /// than each fetching the JWKS. No fetch is started if the JWKS was fetched less than the
+    /// minimum fetch interval ago: this is checked again under the lock of the in-flight fetch, so
+    /// tasks reaching it just after a fetch completed use its keys rather than fetching again.
+    async fn fetch_once(&self) -> Result<(), AxumCognitoError> {
+        let fetch = {
+            let mut in_flight_fetch = self
+                .in_flight_fetch
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if let Some(in_flight_fetch) = &*in_flight_fetch {
+                in_flight_fetch.fetch.clone()
+            } else if !self.can_fetch() {
+                return Ok(());
+            } else {
+                self.start_fetch(&mut in_flight_fetch)
+            }
+        };
+
+        fetch.await.map_err(AxumCognitoError::KeySet)
+    }
+
+    /// Start a fetch of the JWKS that other tasks can join, in place of the fetch in flight
+    fn start_fetch(&self, in_flight_fetch: &mut Option<InFlightFetch>) -> SharedFetch {
+        let sequence = self.next_fetch_sequence();
+        let key_set = self.clone();
+        let fetch = async move {
+            let result = key_set
+                .fetch_as(sequence)
+                .await
+                .map_err(|error| error.to_string());
+            let mut in_flight_fetch = key_set
+                .in_flight_fetch
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if in_flight_fetch
+                .as_ref()
+                .is_some_and(|in_flight_fetch| in_flight_fetch.sequence == sequence)
+            {
+                *in_flight_fetch = None;
+            }
+            result
+        }
+        .boxed()
+        .shared();
+        *in_flight_fetch = Some(InFlightFetch {
+            sequence,
+            fetch: fetch.clone(),
+        });
+        fetch
+    }
+
+    fn cached_key(&self, kid: &str) -> Option<Arc<CachedKey>> {
+        let cache = self.cache.read().unwrap_or_else(PoisonError::into_inner);
+        if cache
+            .retiring
+            .get(kid)
+            .is_some_and(|retire_at| *retire_at <= Instant::now())
+        {
+            return None;
+        }
+        cache.keys.get(kid).cloned()
+    }
+
+    /// Whether the minimum fetch interval elapsed since the last fetch, doubled for each fetch that
+    /// failed in a row up to `MAX_FETCH_BACKOFF`
+    fn can_fetch(&self) -> bool {
+        let cache = self.cache.read().unwrap_or_else(PoisonError::into_inner);
+        let backoff = 2_u32.saturating_pow(cache.failed_fetches.saturating_sub(1));
+        let fetch_interval = self.min_fetch_interval.max(
+            self.min_fetch_interval
+                .saturating_mul(backoff)
+                .min(MAX_FETCH_BACKOFF),
+        );
+        cache
+            .last_attempt
+            .is_none_or(|last_attempt| last_attempt.elapsed() >= fetch_interval)
+    }
+}
+
+/// Replace floating-point timestamps of the standard claims with their value in whole seconds
+fn truncate_timestamps(claims: &mut Value) {
+    for claim in TIMESTAMP_CLAIMS {
+        if let Some(value) = claims.get_mut(claim) {
+            if value.is_f64() {
+                if let Some(timestamp) = numeric_timestamp(value) {
+                    *value = Value::from(timestamp);
+                }
+            }
+        }
+    }
+}
+
+/// RSA algorithm named by the `alg` of a JWK, `None` for keys of any other algorithm
+/// Kid and cached key of an RSA key of the JWKS
+///
+/// Cognito only signs tokens using RS256, compatible providers may also use the other RSA PKCS#1
+/// v1.5 and RSA-PSS algorithms. Keys of other types, or missing a field of an RSA key, cannot
+/// verify tokens and are skipped rather than failing the whole JWKS.
+fn rsa_key(key: Jwk) -> Option<(String, Arc<CachedKey>)> {
+    // `alg` is optional in a JWK, RSA keys without one are used with RS256 as Cognito does
+    let alg = key
+        .alg
+        .as_deref()
+        .or((key.kty == "RSA").then_some("RS256"))?;
+    let algorithm_id = rsa_algorithm(alg)?;
+    let (Some(kid), Some(n), Some(e)) = (key.kid, key.n, key.e) else {
+        tracing::debug!(alg, "Skipping a JWKS key without a kid or RSA parameters");
+        return None;
+    };
+    let (Ok(mut algorithm), Ok(modulus_bits)) = (
+        Algorithm::new_rsa_n_e_b64_verifier(algorithm_id, &n, &e),
+        modulus_bits(&n),
+    ) else {
+        tracing::warn!(kid, "Skipping a malformed RSA key of the JWKS");
+        return None;
+    };
+    algorithm.set_kid(&kid);
+    let cached_key = CachedKey {
+        algorithm,
+        modulus_bits: Some(modulus_bits),
+        local: false,
+    };
+    Some((kid, Arc::new(cached_key)))
+}
+
+fn rsa_algorithm(alg: &str) -> Option<AlgorithmID> {
+    match AlgorithmID::from_str(alg).ok()? {
+        algorithm_id @ (AlgorithmID::RS256
+        | AlgorithmID::RS384
+        | AlgorithmID::RS512
+        | AlgorithmID::PS256
+        | AlgorithmID::PS384
+        | AlgorithmID::PS512) => Some(algorithm_id),
+        _ => None,
+    }
+}
+
+/// HTTP client shared by every key set, so that connections to the Cognito endpoint are reused
+///
+/// Requests time out rather than holding up verifications, or the creation of a validator, when
+/// the endpoint does not respond
+fn http_client() -> Result<&'static reqwest::Client, AxumCognitoError> {
+    static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    if let Some(client) = HTTP_CLIENT.get() {
+        return Ok(client);
+    }
+    let client = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build()?;
+    Ok(HTTP_CLIENT.get_or_init(|| client))
+}
+
+fn parse_jwk_set(body: &[u8]) -> Result<JwkSet, serde_json::Error> {
+    let body = body.strip_prefix(UTF8_BOM).unwrap_or(body);
+    serde_json::from_slice(body)
+}
+
+/// Size in bits of the base64url encoded modulus of an RSA key
+fn modulus_bits(n: &str) -> Result<usize, AxumCognitoError> {
+    let modulus = URL_SAFE_NO_PAD
+        .decode(n.trim_end_matches('='))
+        .map_err(|error| AxumCognitoError::KeySet(error.to_string()))?;
+    let modulus = match modulus.iter().position(|byte| *byte != 0) {
+        Some(first_non_zero) => &modulus[first_non_zero..],
+        None => &[],
+    };
+    Ok(modulus.first().map_or(0, |first| {
+        (modulus.len() - 1) * 8 + (u8::BITS - first.leading_zeros()) as usize
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicUsize, time::Duration};
+
+    use axum::async_trait;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{
+        id_claims, jwk, jwks, rotated_jwks, serve, serve_pool, sign, sign_rotated, sign_with,
+        MockJwks, KID, POOL_ID, REGION, RSA_MODULUS, RSA_PRIVATE_KEY, SMALL_RSA_MODULUS,
+    };
+
+    fn key_set(jwks: Arc<MockJwks>) -> CognitoKeySet {
+        let mut key_set = CognitoKeySet::new(
+            &format!("https://cognito-idp.{REGION}.amazonaws.com"),
+            POOL_ID,
+        );
+        key_set.set_fetcher(jwks);
+        key_set
+    }
+
+    fn verifier() -> Arc<jwt::Verifier> {
+        Arc::new(jwt::Verifier::create().build().unwrap())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_verifications_of_an_unknown_key_share_a_single_fetch() {
+        let mock_jwks = MockJwks::with_delay(jwks(), Duration::from_millis(50));
+        let key_set = key_set(mock_jwks.clone());
+        let verifier = verifier();
+        let token = sign(&id_claims());
+
+        let verifications = (0..100)
+            .map(|_| {
+                let key_set = key_set.clone();
+                let verifier = verifier.clone();
+                let token = token.clone();
+                tokio::spawn(async move { key_set.verify(&token, &verifier).await })
+            })
+            .collect::<Vec<_>>();
+        for verification in verifications {
+            assert!(verification.await.unwrap().is_ok());
+        }
+        assert_eq!(mock_jwks.fetches(), 1);
+    }
+
+    #[tokio::test]
+    async fn unknown_key_is_not_fetched_again_within_the_minimum_fetch_interval() {
+        let mock_jwks = MockJwks::new(json!({ "keys": [] }));
+        let key_set = key_set(mock_jwks.clone());
+        key_set.fetch().await.unwrap();
+        mock_jwks.set(jwks());
+
+        let verification = key_set.verify(&sign(&id_claims()), &verifier()).await;
+
+        assert!(matches!(
+            verification,
+            Err(AxumCognitoError::UnknownKeyId(_))
+        ));
+        assert_eq!(mock_jwks.fetches(), 1);
+    }
+
+    /// JWKS fetcher whose fetches always fail, counting them
+    #[derive(Default)]
+    struct FailingJwks {
+        fetches: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl JwksFetcher for FailingJwks {
+        async fn fetch(&self) -> Result<Value, AxumCognitoError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            Err(AxumCognitoError::KeySet(
+                "JWKS endpoint unavailable".to_string(),
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_fetch_is_not_retried_within_the_minimum_fetch_interval() {
+        let failing_jwks = Arc::new(FailingJwks::default());
+        let mut key_set = CognitoKeySet::new(
+            &format!("https://cognito-idp.{REGION}.amazonaws.com"),
+            POOL_ID,
+        );
+        key_set.set_fetcher(failing_jwks.clone());
+        let token = sign(&id_claims());
+
+        assert!(key_set.fetch().await.is_err());
+        for _ in 0..10 {
+            assert!(key_set.verify(&token, &verifier()).await.is_err());
+        }
+
+        assert_eq!(failing_jwks.fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn fetch_interval_backs_off_while_fetches_keep_failing() {
+        let mut key_set = key_set(MockJwks::new(jwks()));
+        key_set.set_min_fetch_interval(Duration::from_secs(60));
+        let failed_at = |failed_fetches, ago| {
+            let mut cache = key_set.cache.write().unwrap();
+            cache.failed_fetches = failed_fetches;
+            cache.last_attempt = Instant::now().checked_sub(Duration::from_secs(ago));
+        };
+
+        failed_at(1, 61);
+        assert!(key_set.can_fetch());
+        failed_at(3, 200);
+        assert!(!key_set.can_fetch());
+        failed_at(3, 241);
+        assert!(key_set.can_fetch());
+        failed_at(20, 15 * 60 + 1);
+        assert!(key_set.can_fetch());
+    }
+
+    #[tokio::test]
+    async fn unknown_key_is_fetched_again_after_the_minimum_fetch_interval() {
+        let mock_jwks = MockJwks::new(json!({ "keys": [jwk("other", "RS256", "AQAB")] }));
+        let mut key_set = key_set(mock_jwks.clone());
+        key_set.set_min_fetch_interval(Duration::ZERO);
+        key_set.fetch().await.unwrap();
+        mock_jwks.set(jwks());
+
+        let verification = key_set.verify(&sign(&id_claims()), &verifier()).await;
+
+        assert!(verification.is_ok());
+        assert_eq!(mock_jwks.fetches(), 2);
+    }
+
+    #[tokio::test]
+    async fn old_key_verifies_during_the_grace_period_after_a_notified_rotation() {
+        let mock_jwks = MockJwks::new(jwks());
+        let key_set = key_set(mock_jwks.clone());
+        key_set.fetch().await.unwrap();
+        mock_jwks.set(rotated_jwks());
+
+        key_set.rotate().await.unwrap();
+
+        let verifier = verifier();
+        assert!(key_set.verify(&sign(&id_claims()), &verifier).await.is_ok());
+        assert!(key_set
+            .verify(&sign_rotated(&id_claims()), &verifier)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn old_key_is_rejected_after_the_grace_period() {
+        let mock_jwks = MockJwks::new(jwks());
+        let mut key_set = key_set(mock_jwks.clone());
+        key_set.set_rotation_grace(Duration::ZERO);
+        key_set.fetch().await.unwrap();
+        mock_jwks.set(rotated_jwks());
+
+        key_set.rotate().await.unwrap();
+
+        let verification = key_set.verify(&sign(&id_claims()), &verifier()).await;
+        assert!(matches!(
+            verification,
+            Err(AxumCognitoError::UnknownKeyId(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn key_removed_from_the_jwks_is_retired_without_a_notified_rotation() {
+        let mock_jwks = MockJwks::new(jwks());
+        let mut key_set = key_set(mock_jwks.clone());
+        key_set.set_rotation_grace(Duration::ZERO);
+        key_set.fetch().await.unwrap();
+        mock_jwks.set(rotated_jwks());
+
+        key_set.fetch().await.unwrap();
+
+        let verification = key_set.verify(&sign(&id_claims()), &verifier()).await;
+        assert!(matches!(
+            verification,
+            Err(AxumCognitoError::UnknownKeyId(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn notified_rotation_fetches_again_rather_than_joining_the_fetch_in_flight() {
+        let mock_jwks = MockJwks::with_delay(jwks(), Duration::from_millis(100));
+        let key_set = key_set(mock_jwks.clone());
+        let verifier = verifier();
+        // Starts a fetch of the JWKS from before the rotation
+        let verification = tokio::spawn({
+            let key_set = key_set.clone();
+            let verifier = verifier.clone();
+            async move { key_set.verify(&sign(&id_claims()), &verifier).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        mock_jwks.set(rotated_jwks());
+
+        key_set.rotate().await.unwrap();
+
+        assert!(verification.await.unwrap().is_ok());
+        assert!(key_set
+            .verify(&sign_rotated(&id_claims()), &verifier)
+            .await
+            .is_ok());
+        assert_eq!(mock_jwks.fetches(), 2);
+    }
+
+    #[tokio::test]
+    async fn jwks_served_with_a_bom_and_a_text_content_type_is_parsed() {
+        let body = [UTF8_BOM, jwks().to_string().as_bytes()].concat();
+        let endpoint = serve_pool(([(http::header::CONTENT_TYPE, "text/plain")], body)).await;
+        let key_set = CognitoKeySet::new(&endpoint, POOL_ID);
+
+        key_set.fetch().await.unwrap();
+
+        assert_eq!(key_set.fetched_key_count(), 1);
+        assert!(key_set
+            .verify(&sign(&id_claims()), &verifier())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn key_smaller_than_the_minimum_size_is_rejected() {
+        let mut key_set = key_set(MockJwks::new(json!({
+            "keys": [jwk(KID, "RS256", SMALL_RSA_MODULUS)]
+        })));
+        key_set.set_min_rsa_bits(2048);
+
+        let verification = key_set.verify(&sign(&id_claims()), &verifier()).await;
+
+        assert!(matches!(verification, Err(AxumCognitoError::KeyTooSmall(kid)) if kid == KID));
+    }
+
+    #[tokio::test]
+    async fn key_of_the_minimum_size_is_accepted() {
+        let mut key_set = key_set(MockJwks::new(jwks()));
+        key_set.set_min_rsa_bits(2048);
+
+        let verification = key_set.verify(&sign(&id_claims()), &verifier()).await;
+
+        assert!(verification.is_ok());
+    }
+
+    fn sign_pss(alg: AlgorithmID) -> String {
+        sign_with(
+            &id_claims(),
+            &json!({ "alg": alg.to_string(), "kid": KID }),
+            alg,
+            RSA_PRIVATE_KEY,
+        )
+    }
+
+    #[tokio::test]
+    async fn rsa_pss_tokens_verify_against_a_rsa_pss_key() {
+        for alg in [AlgorithmID::PS256, AlgorithmID::PS384, AlgorithmID::PS512] {
+            let jwks = json!({ "keys": [jwk(KID, &alg.to_string(), RSA_MODULUS)] });
+            let key_set = key_set(MockJwks::new(jwks));
+
+            let verification = key_set.verify(&sign_pss(alg), &verifier()).await;
+
+            assert!(verification.is_ok(), "{alg:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rs256_token_is_rejected_by_a_ps256_key() {
+        let jwks = json!({ "keys": [jwk(KID, "PS256", RSA_MODULUS)] });
+        let key_set = key_set(MockJwks::new(jwks));
+
+        let verification = key_set.verify(&sign(&id_claims()), &verifier()).await;
+
+        assert!(verification.is_err());
+    }
+
+    #[tokio::test]
+    async fn rsa_keys_of_a_jwks_mixing_key_types_verify_tokens() {
+        let ec_key = json!({
+            "kid": "ec-key",
+            "alg": "ES256",
+            "kty": "EC",
+            "use": "sig",
+            "crv": "P-256",
+            "x": "f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU",
+            "y": "x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0",
+        });
+        let rsa_key_without_alg =
+            json!({ "kid": KID, "kty": "RSA", "n": RSA_MODULUS, "e": "AQAB" });
+        let jwks = json!({ "keys": [ec_key, rsa_key_without_alg] });
+        let key_set = key_set(MockJwks::new(jwks));
+
+        let verification = key_set.verify(&sign(&id_claims()), &verifier()).await;
+
+        assert!(verification.is_ok());
+        assert_eq!(key_set.fetched_key_count(), 1);
+    }
+
+    /// JWKS padded with trailing whitespace to `size` bytes
+    fn padded_jwks(size: usize) -> String {
+        let jwks = jwks().to_string();
+        format!("{jwks}{}", " ".repeat(size - jwks.len()))
+    }
+
+    #[tokio::test]
+    async fn jwks_larger_than_the_maximum_size_is_rejected() {
+        let endpoint = serve_pool(padded_jwks(DEFAULT_MAX_JWKS_SIZE + 1)).await;
+        let key_set = CognitoKeySet::new(&endpoint, POOL_ID);
+
+        let fetch = key_set.fetch().await;
+
+        assert!(matches!(
+            fetch,
+            Err(AxumCognitoError::KeySet(message)) if message.contains("larger than the maximum")
+        ));
+        assert_eq!(key_set.fetched_key_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn streamed_jwks_is_rejected_as_soon_as_it_exceeds_the_maximum_size() {
+        let router = axum::Router::new().route(
+            &format!("/{POOL_ID}/.well-known/jwks.json"),
+            axum::routing::get(|| async {
+                let chunks = [padded_jwks(1024), " ".repeat(1024)];
+                axum::body::Body::from_stream(futures_util::stream::iter(
+                    chunks.map(Ok::<_, std::convert::Infallible>),
+                ))
+            }),
+        );
+        let endpoint = serve(router).await;
+        let mut key_set = CognitoKeySet::new(&endpoint, POOL_ID);
+        key_set.set_max_jwks_size(1536);
+
+        let fetch = key_set.fetch().await;
+
+        assert!(matches!(fetch, Err(AxumCognitoError::KeySet(_))));
+    }
+
+    #[tokio::test]
+    async fn jwks_within_a_raised_maximum_size_is_parsed() {
+        let endpoint = serve_pool(padded_jwks(DEFAULT_MAX_JWKS_SIZE + 1)).await;
+        let mut key_set = CognitoKeySet::new(&endpoint, POOL_ID);
+        key_set.set_max_jwks_size(2 * DEFAULT_MAX_JWKS_SIZE);
+
+        key_set.fetch().await.unwrap();
+
+        assert_eq!(key_set.fetched_key_count(), 1);
+    }
+}
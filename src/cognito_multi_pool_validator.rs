@@ -0,0 +1,434 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use axum::extract::Request;
+use http::header;
+use jsonwebtokens as jwt;
+use serde_json::Value;
+
+use crate::{
+    bearer_token::bearer_token, AxumCognitoError, CognitoValidator, OAuthTokenType, PoolConfig,
+};
+
+/// Resolves the tenant a request belongs to
+pub type TenantResolver = Arc<dyn Fn(&Request) -> Option<String> + Send + Sync>;
+
+/// How the validator of a request is found
+#[derive(Clone)]
+enum Routing {
+    /// By the tenant the resolver returns for the request
+    Tenant(TenantResolver),
+    /// By the unverified `iss` claim of the token, matched against the issuer of each pool
+    Issuer,
+}
+
+/// Validator for JWT tokens issued by several Cognito pools, one per tenant
+///
+/// The tenant of each request is found using a resolver and the token is then verified by the
+/// validator registered for that tenant, so only the pool and client ids of that tenant are
+/// accepted. Alternatively tokens can be routed to the pool matching their `iss` claim, see
+/// `from_issuers`.
+///
+/// Pools can be registered and unregistered at runtime, e.g. when pools are provisioned for new
+/// customers, while requests are being verified.
+#[derive(Clone)]
+pub struct CognitoMultiPoolValidator<UC>
+where
+    UC: for<'de> serde::Deserialize<'de>,
+{
+    validators: Arc<RwLock<HashMap<String, Arc<CognitoValidator<UC>>>>>,
+    routing: Routing,
+}
+
+impl<UC> CognitoMultiPoolValidator<UC>
+where
+    UC: for<'de> serde::Deserialize<'de>,
+{
+    /// Create a new `CognitoMultiPoolValidator`
+    ///
+    /// # Arguments
+    /// * `validators` - validator of each tenant, keyed by tenant
+    /// * `resolver` - returns the tenant of a request, or `None` if it has none
+    ///
+    /// # Returns
+    /// a new `CognitoMultiPoolValidator`
+    #[must_use]
+    pub fn new(
+        validators: HashMap<String, CognitoValidator<UC>>,
+        resolver: impl Fn(&Request) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            validators: Arc::new(RwLock::new(
+                validators
+                    .into_iter()
+                    .map(|(tenant, validator)| (tenant, Arc::new(validator)))
+                    .collect(),
+            )),
+            routing: Routing::Tenant(Arc::new(resolver)),
+        }
+    }
+
+    /// Create a new `CognitoMultiPoolValidator` routing each token to the pool that issued it
+    ///
+    /// The `iss` and `token_use` claims of the token are read before the token is verified and
+    /// matched against the issuer and token type of each validator, tokens matching no validator
+    /// are rejected. The token is then verified by the matching validator, so forged claims only
+    /// select the pool whose keys the signature is checked against. A pool can have both an Id
+    /// and an Access validator, validators are keyed by issuer and token use, e.g.
+    /// `https://cognito-idp.eu-west-1.amazonaws.com/eu-west-1_abc123 id`.
+    ///
+    /// # Arguments
+    /// * `validators` - validator of each pool and token type
+    ///
+    /// # Returns
+    /// a new `CognitoMultiPoolValidator`
+    #[must_use]
+    pub fn from_issuers(validators: Vec<CognitoValidator<UC>>) -> Self {
+        Self {
+            validators: Arc::new(RwLock::new(
+                validators
+                    .into_iter()
+                    .map(|validator| (issuer_key(&validator), Arc::new(validator)))
+                    .collect(),
+            )),
+            routing: Routing::Issuer,
+        }
+    }
+
+    /// Register the pool of a tenant, replacing the pool previously registered for it
+    ///
+    /// The JWKS of the pool is fetched lazily, when the first token of the pool is verified, so
+    /// registering a newly provisioned pool does not wait for Cognito. Key rotations are then
+    /// picked up when a token signed by an unknown key is verified.
+    ///
+    /// # Arguments
+    /// * `tenant` - tenant the pool belongs to, as returned by the resolver, or any unique key
+    ///   such as the issuer and token use when routing by issuer
+    /// * `token_type` - type of token to validate, one of `ID` or `Access`
+    /// * `pool_config` - Cognito pool and clients to accept tokens from
+    ///
+    /// # Errors
+    /// Returns an error if the validator of the pool cannot be created, e.g. a local public key
+    /// of the pool is invalid
+    pub async fn register_pool(
+        &self,
+        tenant: &str,
+        token_type: OAuthTokenType,
+        pool_config: PoolConfig,
+    ) -> Result<(), AxumCognitoError> {
+        let validator = CognitoValidator::from_pool_config_lazily(token_type, pool_config).await?;
+        self.register_validator(tenant, validator);
+        Ok(())
+    }
+
+    /// Register the pool of a tenant using a validator built beforehand, replacing the pool
+    /// previously registered for it
+    ///
+    /// Lets the options of the validator, such as `with_min_rsa_bits` or `with_claims_cache`,
+    /// apply to the pool.
+    ///
+    /// # Arguments
+    /// * `tenant` - tenant the pool belongs to, as returned by the resolver, or any unique key
+    ///   such as the issuer and token use when routing by issuer
+    /// * `validator` - validator of the tokens of the pool
+    pub fn register_validator(&self, tenant: &str, validator: CognitoValidator<UC>) {
+        self.validators
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(tenant.to_string(), Arc::new(validator));
+    }
+
+    /// Unregister a pool, tokens issued by it are no longer accepted for any tenant
+    ///
+    /// Requests already being verified by the pool are not affected
+    ///
+    /// # Returns
+    /// `true` if the pool was registered for at least one tenant
+    pub fn unregister_pool(&self, pool_id: &str) -> bool {
+        let mut validators = self
+            .validators
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let registered = validators.len();
+        validators.retain(|_, validator| validator.pool_config().pool_id() != pool_id);
+        validators.len() != registered
+    }
+
+    /// Find the validator of the tenant a request belongs to
+    ///
+    /// When routing by issuer, the token is read from the `Authorization` header
+    ///
+    /// # Arguments
+    /// * `request` - request to resolve the tenant of
+    ///
+    /// # Returns
+    /// the validator of the tenant, or `None` if the request has no known tenant
+    #[must_use]
+    pub fn resolve(&self, request: &Request) -> Option<Arc<CognitoValidator<UC>>> {
+        let token = bearer_token(request.headers(), &header::AUTHORIZATION).ok();
+        self.resolve_with_token(request, token)
+    }
+
+    /// Find the validator of the tenant a request belongs to, routing by issuer the token read
+    /// from the request by the caller, e.g. from the header or the cookie configured on the layer
+    pub(crate) fn resolve_with_token(
+        &self,
+        request: &Request,
+        token: Option<&str>,
+    ) -> Option<Arc<CognitoValidator<UC>>> {
+        match &self.routing {
+            Routing::Tenant(resolver) => {
+                let tenant = resolver(request)?;
+                self.validators
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get(&tenant)
+                    .cloned()
+            }
+            Routing::Issuer => self.resolve_issuer(token?),
+        }
+    }
+
+    /// Find the validator whose issuer and token type match the unverified `iss` and `token_use`
+    /// claims of a token
+    ///
+    /// # Returns
+    /// the validator of the pool, or `None` if the token cannot be decoded or no validator matches
+    #[must_use]
+    pub fn resolve_issuer(&self, token: &str) -> Option<Arc<CognitoValidator<UC>>> {
+        let token_data = jwt::raw::decode_only(token).ok()?;
+        let claim = |name| token_data.claims.get(name).and_then(Value::as_str);
+        let issuer = claim("iss")?.trim_end_matches('/');
+        let token_use = claim("token_use")?;
+        self.validators
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .find(|validator| {
+                validator.issuer().trim_end_matches('/') == issuer
+                    && validator.token_type().token_use() == token_use
+            })
+            .cloned()
+    }
+
+    /// Validators of all tenants currently registered
+    pub(crate) fn validators(&self) -> Vec<Arc<CognitoValidator<UC>>> {
+        self.validators
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Key of a validator routed to by issuer, as the same pool can have an Id and an Access validator
+fn issuer_key<UC>(validator: &CognitoValidator<UC>) -> String
+where
+    UC: for<'de> serde::Deserialize<'de>,
+{
+    format!(
+        "{} {}",
+        validator.issuer().trim_end_matches('/'),
+        validator.token_type().token_use()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{body::Body, Json};
+
+    use super::*;
+    use crate::{
+        cognito_validator::unix_timestamp,
+        test_support::{
+            access_claims, access_validator, id_claims, id_validator, jwks, pool_config,
+            serve_pool, sign, validator_with, MockJwks, CLIENT_ID, POOL_ID, REGION,
+        },
+    };
+
+    /// Multi-pool validator without any pool, reading the tenant from the `x-tenant` header
+    fn by_tenant_header() -> CognitoMultiPoolValidator<Value> {
+        CognitoMultiPoolValidator::new(HashMap::new(), |request| {
+            request
+                .headers()
+                .get("x-tenant")
+                .and_then(|tenant| tenant.to_str().ok())
+                .map(str::to_string)
+        })
+    }
+
+    fn request_of_tenant(tenant: &str) -> Request {
+        Request::builder()
+            .header("x-tenant", tenant)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn registered_pool_keeps_the_options_of_its_validator() {
+        let validator = by_tenant_header();
+        validator.register_validator(
+            "acme",
+            id_validator()
+                .await
+                .with_max_token_age(Duration::from_secs(60)),
+        );
+        let mut claims = id_claims();
+        claims["iat"] = (unix_timestamp() - 600).into();
+
+        let pool = validator.resolve(&request_of_tenant("acme")).unwrap();
+
+        assert_eq!(pool.validate_token(&sign(&claims)).await.unwrap(), None);
+        assert!(pool
+            .validate_token(&sign(&id_claims()))
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn pool_registered_after_startup_fetches_its_jwks_at_its_first_token() {
+        let validator = by_tenant_header();
+        validator
+            .register_pool(
+                "initech",
+                OAuthTokenType::Id,
+                // Nothing listens on the discard port, the pool is only reached once a token of
+                // the pool is verified
+                pool_config().with_endpoint("http://127.0.0.1:9"),
+            )
+            .await
+            .unwrap();
+        let endpoint = serve_pool(Json(jwks())).await;
+        validator
+            .register_pool(
+                "acme",
+                OAuthTokenType::Id,
+                pool_config().with_endpoint(&endpoint),
+            )
+            .await
+            .unwrap();
+        let mut claims = id_claims();
+        claims["iss"] = format!("{endpoint}/{POOL_ID}").into();
+
+        let pool = validator.resolve(&request_of_tenant("acme")).unwrap();
+
+        assert!(pool.validate_token(&sign(&claims)).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn unregistered_pool_is_no_longer_resolved() {
+        let validator = by_tenant_header();
+        validator.register_validator("acme", id_validator().await);
+
+        assert!(validator.unregister_pool(POOL_ID));
+
+        assert!(validator.resolve(&request_of_tenant("acme")).is_none());
+        assert!(!validator.unregister_pool(POOL_ID));
+    }
+
+    #[tokio::test]
+    async fn tokens_are_verified_against_the_client_ids_of_their_tenant() {
+        let validator = by_tenant_header();
+        validator.register_validator("acme", id_validator().await);
+        validator.register_validator(
+            "globex",
+            validator_with(
+                OAuthTokenType::Id,
+                PoolConfig::new(&["globex-client"], POOL_ID, REGION),
+                MockJwks::new(jwks()),
+            )
+            .await,
+        );
+        let token = sign(&id_claims());
+
+        let acme = validator.resolve(&request_of_tenant("acme")).unwrap();
+        let globex = validator.resolve(&request_of_tenant("globex")).unwrap();
+
+        assert!(acme.validate_token(&token).await.unwrap().is_some());
+        assert_eq!(globex.validate_token(&token).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn request_of_an_unknown_tenant_is_not_resolved() {
+        let validator = by_tenant_header();
+        validator.register_validator("acme", id_validator().await);
+
+        assert!(validator.resolve(&request_of_tenant("initech")).is_none());
+        assert!(validator.resolve(&Request::new(Body::empty())).is_none());
+    }
+
+    const OTHER_POOL_ID: &str = "eu-west-1_OtherPool";
+
+    /// Multi-pool validator routing tokens by issuer between the test pool and `OTHER_POOL_ID`
+    async fn by_issuer() -> CognitoMultiPoolValidator<Value> {
+        let other = validator_with(
+            OAuthTokenType::Id,
+            PoolConfig::new(&[CLIENT_ID], OTHER_POOL_ID, REGION),
+            MockJwks::new(jwks()),
+        )
+        .await;
+        CognitoMultiPoolValidator::from_issuers(vec![id_validator().await, other])
+    }
+
+    #[tokio::test]
+    async fn tokens_are_routed_to_the_pool_of_their_issuer() {
+        let validator = by_issuer().await;
+        let mut claims = id_claims();
+        claims["iss"] =
+            format!("https://cognito-idp.{REGION}.amazonaws.com/{OTHER_POOL_ID}").into();
+        let token = sign(&claims);
+        let request = Request::builder()
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap();
+
+        let pool = validator.resolve(&request).unwrap();
+
+        assert_eq!(pool.pool_config().pool_id(), OTHER_POOL_ID);
+        assert!(pool.validate_token(&token).await.unwrap().is_some());
+        assert_eq!(
+            validator
+                .resolve_issuer(&sign(&id_claims()))
+                .unwrap()
+                .pool_config()
+                .pool_id(),
+            POOL_ID
+        );
+    }
+
+    #[tokio::test]
+    async fn token_of_an_unknown_issuer_is_not_resolved() {
+        let validator = by_issuer().await;
+        let mut claims = id_claims();
+        claims["iss"] = "https://cognito-idp.eu-west-1.amazonaws.com/eu-west-1_Unknown".into();
+
+        assert!(validator.resolve_issuer(&sign(&claims)).is_none());
+        assert!(validator.resolve_issuer("not a token").is_none());
+        assert!(validator.resolve(&Request::new(Body::empty())).is_none());
+    }
+
+    #[tokio::test]
+    async fn id_and_access_tokens_of_a_pool_are_routed_to_their_own_validator() {
+        let validator = CognitoMultiPoolValidator::from_issuers(vec![
+            id_validator().await,
+            access_validator().await,
+        ]);
+
+        let id = validator.resolve_issuer(&sign(&id_claims())).unwrap();
+        let access = validator.resolve_issuer(&sign(&access_claims())).unwrap();
+
+        assert_eq!(id.token_type().token_use(), "id");
+        assert_eq!(access.token_type().token_use(), "access");
+        assert!(access
+            .validate_token(&sign(&access_claims()))
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
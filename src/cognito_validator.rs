@@ -1,8 +1,32 @@
-use std::marker::PhantomData;
+use std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use crate::AxumCognitoError;
+use axum::response::Response;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::digest;
+use serde_json::Value;
+
+use crate::{
+    claims_cache::ClaimsCache,
+    cognito_auth_layer::{create_unauthorized_response, DEFAULT_REALM, INVALID_TOKEN},
+    cognito_key_set::CognitoKeySet,
+    unverified_header, AuthContext, AuthorizationRule, AxumCognitoError, JwksFetcher,
+    VerifiedToken,
+};
 use jsonwebtokens as jwt;
-use jsonwebtokens_cognito::KeySet;
+
+/// How far in the future a token's `iat` has to be to suggest the system clock is wrong
+const CLOCK_SKEW_WARNING_THRESHOLD: Duration = Duration::from_secs(5 * 60);
+
+/// Minimum time between two warnings about tokens issued to the same deprecated client
+const DEPRECATED_CLIENT_WARNING_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Copy, Clone)]
 pub enum OAuthTokenType {
@@ -10,14 +34,267 @@ pub enum OAuthTokenType {
     Access,
 }
 
+impl OAuthTokenType {
+    /// Value of the `token_use` claim of tokens of this type
+    pub(crate) fn token_use(self) -> &'static str {
+        match self {
+            Self::Id => "id",
+            Self::Access => "access",
+        }
+    }
+
+    /// Claim holding the client id of tokens of this type
+    pub(crate) fn client_id_claim(self) -> &'static str {
+        match self {
+            Self::Id => "aud",
+            Self::Access => "client_id",
+        }
+    }
+}
+
+/// How the client id of a token, its audience, is checked against the accepted client ids
+///
+/// The client id is held by the `aud` claim of ID tokens and the `client_id` claim of access
+/// tokens
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AudiencePolicy {
+    /// The client id must be present and accepted
+    #[default]
+    Required,
+    /// The client id must be accepted if present, tokens without one are accepted
+    OptionalIfPresent,
+    /// The client id is not checked
+    Ignored,
+}
+
+/// Public key provided out-of-band, see `PoolConfig::with_local_public_key`
+#[derive(Clone, Debug)]
+struct LocalPublicKey {
+    pem: Vec<u8>,
+    kid: String,
+    alg: String,
+}
+
+/// Cognito pool and clients a `CognitoValidator` accepts tokens from
+///
+/// Along with the pool, it holds the verification leeway and authorization rules specific to
+/// the pool, so each pool of a `CognitoMultiPoolValidator` can have its own
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    client_ids: Vec<String>,
+    pool_id: String,
+    region: String,
+    leeway: u32,
+    startup_grace: Option<Duration>,
+    audience_policy: AudiencePolicy,
+    fips: bool,
+    endpoint: Option<String>,
+    max_jwks_size: Option<usize>,
+    local_public_keys: Vec<LocalPublicKey>,
+    rules: Vec<AuthorizationRule>,
+}
+
+impl PoolConfig {
+    /// Create a pool configuration
+    ///
+    /// # Arguments
+    /// * `cognito_client_ids` - client ids of the Cognito clients whose tokens are accepted
+    /// * `cognito_pool_id` - pool id for the Cognito pool
+    /// * `cognito_region` - AWS region of the Cognito pool
+    #[must_use]
+    pub fn new(cognito_client_ids: &[&str], cognito_pool_id: &str, cognito_region: &str) -> Self {
+        Self {
+            client_ids: cognito_client_ids.iter().map(ToString::to_string).collect(),
+            pool_id: cognito_pool_id.to_string(),
+            region: cognito_region.to_string(),
+            leeway: 0,
+            startup_grace: None,
+            audience_policy: AudiencePolicy::default(),
+            fips: false,
+            endpoint: None,
+            max_jwks_size: None,
+            local_public_keys: Vec::new(),
+            rules: Vec::new(),
+        }
+    }
+
+    /// Set the leeway, in seconds, allowed when checking the `exp`, `nbf` and `iat` claims
+    #[must_use]
+    pub fn with_leeway(mut self, leeway: u32) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// Tolerate a clock skew of up to `startup_grace` during `startup_grace` after the validator
+    /// is created
+    ///
+    /// Containers can start with a badly skewed clock, during the grace period tokens failing
+    /// the `exp`, `nbf` or `iat` checks are checked again using `startup_grace` as the leeway
+    #[must_use]
+    pub fn with_startup_grace(mut self, startup_grace: Duration) -> Self {
+        self.startup_grace = Some(startup_grace);
+        self
+    }
+
+    /// Set how the client id of tokens is checked, `AudiencePolicy::Required` by default
+    #[must_use]
+    pub fn with_audience_policy(mut self, audience_policy: AudiencePolicy) -> Self {
+        self.audience_policy = audience_policy;
+        self
+    }
+
+    /// Set whether the pool is reached through the FIPS endpoint of its region, disabled by
+    /// default
+    ///
+    /// Both the expected issuer of tokens and the JWKS url then use the
+    /// `cognito-idp-fips.<region>.amazonaws.com` hostname, as required by government deployments
+    #[must_use]
+    pub fn with_fips(mut self, fips: bool) -> Self {
+        self.fips = fips;
+        self
+    }
+
+    /// Reach the pool through `endpoint` rather than the Cognito endpoint of its region, e.g. a
+    /// local emulator or a proxy
+    ///
+    /// Both the expected issuer of tokens, `<endpoint>/<pool id>`, and the JWKS url derive from
+    /// the endpoint, which takes precedence over `with_fips`
+    #[must_use]
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = Some(endpoint.to_string());
+        self
+    }
+
+    /// Set the maximum size, in bytes, of the JWKS response body, 256 KiB by default
+    ///
+    /// Guards against a misbehaving or malicious JWKS endpoint returning an enormous body: the
+    /// fetch is aborted with an error as soon as the body exceeds `max_jwks_size`. Also applies to
+    /// the discovery document fetched by `CognitoValidator::new_checked`, but not to JWKS returned
+    /// by a custom `JwksFetcher`.
+    #[must_use]
+    pub fn with_max_jwks_size(mut self, max_jwks_size: usize) -> Self {
+        self.max_jwks_size = Some(max_jwks_size);
+        self
+    }
+
+    /// Register a public key, in PEM format, tokens can be signed with in addition to the keys of
+    /// the pool's JWKS
+    ///
+    /// Useful for tests and air-gapped setups signing tokens with their own key: when a local
+    /// key is registered the JWKS is not fetched as the validator is created, only when a token
+    /// is signed by an unknown key. The key is checked when the validator is created.
+    ///
+    /// # Arguments
+    /// * `pem` - public key, RSA or ECDSA, in PEM format
+    /// * `kid` - key id of the key, as set in the header of the tokens it signs
+    /// * `alg` - algorithm of the key, e.g. `RS256`
+    #[must_use]
+    pub fn with_local_public_key(mut self, pem: &[u8], kid: &str, alg: &str) -> Self {
+        self.local_public_keys.push(LocalPublicKey {
+            pem: pem.to_vec(),
+            kid: kid.to_string(),
+            alg: alg.to_string(),
+        });
+        self
+    }
+
+    /// Only authorise tokens of users belonging to all of `groups`
+    #[must_use]
+    pub fn with_required_groups(mut self, groups: &[&str]) -> Self {
+        self.rules.extend(
+            groups
+                .iter()
+                .map(|group| AuthorizationRule::Group((*group).to_string())),
+        );
+        self
+    }
+
+    /// Client ids of the Cognito clients whose tokens are accepted
+    #[must_use]
+    pub fn client_ids(&self) -> &[String] {
+        &self.client_ids
+    }
+
+    /// Pool id for the Cognito pool
+    #[must_use]
+    pub fn pool_id(&self) -> &str {
+        &self.pool_id
+    }
+
+    /// AWS region of the Cognito pool
+    #[must_use]
+    pub fn region(&self) -> &str {
+        &self.region
+    }
+
+    /// Leeway, in seconds, allowed when checking the `exp`, `nbf` and `iat` claims
+    #[must_use]
+    pub fn leeway(&self) -> u32 {
+        self.leeway
+    }
+
+    /// How the client id of tokens is checked
+    #[must_use]
+    pub fn audience_policy(&self) -> AudiencePolicy {
+        self.audience_policy
+    }
+
+    /// Whether the pool is reached through the FIPS endpoint of its region
+    #[must_use]
+    pub fn fips(&self) -> bool {
+        self.fips
+    }
+
+    /// Endpoint the pool is reached through when not the Cognito endpoint of its region
+    #[must_use]
+    pub fn endpoint(&self) -> Option<&str> {
+        self.endpoint.as_deref()
+    }
+
+    /// Authorization rules specific to the pool
+    #[must_use]
+    pub fn rules(&self) -> &[AuthorizationRule] {
+        &self.rules
+    }
+
+    /// Base url of the endpoint the issuer and JWKS url of the pool derive from
+    fn endpoint_url(&self) -> String {
+        match &self.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None if self.fips => format!("https://cognito-idp-fips.{}.amazonaws.com", self.region),
+            None => format!("https://cognito-idp.{}.amazonaws.com", self.region),
+        }
+    }
+}
+
+/// Lenient verification of tokens shortly after the validator is created
+#[derive(Clone)]
+struct StartupGrace {
+    until: Instant,
+    token_verifier: jwt::Verifier,
+}
+
 /// Validator for JWT tokens issued by Cognito
 #[derive(Clone)]
 pub struct CognitoValidator<UC>
 where
     UC: for<'de> serde::Deserialize<'de>,
 {
-    key_set: KeySet,
+    token_type: OAuthTokenType,
+    pool_config: PoolConfig,
+    key_set: CognitoKeySet,
     token_verifier: jwt::Verifier,
+    startup_grace: Option<StartupGrace>,
+    clock_skew_warned: Arc<AtomicBool>,
+    deprecated_client_ids: HashSet<String>,
+    /// Time a token issued to each deprecated client was last warned about, shared by clones so
+    /// that the warnings are rate limited across them
+    deprecation_warnings: Arc<Mutex<HashMap<String, Instant>>>,
+    require_sub: bool,
+    max_token_age: Option<Duration>,
+    allowed_typ: Option<Vec<String>>,
+    claims_cache: Option<ClaimsCache>,
+    lenient_claims: bool,
     phantom_data: PhantomData<UC>,
 }
 
@@ -44,31 +321,330 @@ where
         cognito_pool_id: &str,
         cognito_region: &str,
     ) -> Result<Self, AxumCognitoError> {
-        let key_set = KeySet::new(cognito_region, cognito_pool_id)
-            .map_err(|error| AxumCognitoError::JsonwebtokensCognito(error.to_string()))?;
-        key_set
-            .prefetch_jwks()
-            .await
-            .map_err(|error| AxumCognitoError::JsonwebtokensCognito(error.to_string()))?;
-
-        let token_verifier = match token_type {
-            OAuthTokenType::Id => key_set
-                .new_id_token_verifier(&[cognito_client_id])
-                .build()?,
-            OAuthTokenType::Access => key_set
-                .new_access_token_verifier(&[cognito_client_id])
-                .build()?,
+        Self::from_pool_config(
+            token_type,
+            PoolConfig::new(&[cognito_client_id], cognito_pool_id, cognito_region),
+        )
+        .await
+    }
+
+    /// Create a new `CognitoValidator` accepting tokens from any of the clients of a pool
+    ///
+    /// # Arguments
+    /// * `token_type` - type of token to validate, one of `ID` or `Access`
+    /// * `pool_config` - Cognito pool and clients to accept tokens from
+    ///
+    /// # Returns
+    /// a new `CognitoValidator`
+    ///
+    /// # Errors
+    /// Returns an error if the `CognitoValidator` cannot be created
+    pub async fn from_pool_config(
+        token_type: OAuthTokenType,
+        pool_config: PoolConfig,
+    ) -> Result<Self, AxumCognitoError> {
+        Self::build(token_type, pool_config, None, true).await
+    }
+
+    /// Create a new `CognitoValidator` fetching the JWKS of the pool at the first token verified,
+    /// rather than up front
+    pub(crate) async fn from_pool_config_lazily(
+        token_type: OAuthTokenType,
+        pool_config: PoolConfig,
+    ) -> Result<Self, AxumCognitoError> {
+        Self::build(token_type, pool_config, None, false).await
+    }
+
+    /// Create a new `CognitoValidator`, failing fast if the configuration looks wrong
+    ///
+    /// In addition to prefetching the JWKS, even when local public keys are registered, checks
+    /// that the issuer derived from the pool id and region, or endpoint, is reachable and that the
+    /// JWKS holds at least one usable key. Catches e.g. a mistyped pool id at boot rather than at
+    /// the first request.
+    ///
+    /// # Arguments
+    /// * `token_type` - type of token to validate, one of `ID` or `Access`
+    /// * `pool_config` - Cognito pool and clients to accept tokens from
+    ///
+    /// # Returns
+    /// a new `CognitoValidator`
+    ///
+    /// # Errors
+    /// Returns an `AxumCognitoError::InvalidConfiguration` detailing the problem if the issuer is
+    /// not reachable or the JWKS holds no usable key, or any other error if the `CognitoValidator`
+    /// cannot be created
+    pub async fn new_checked(
+        token_type: OAuthTokenType,
+        pool_config: PoolConfig,
+    ) -> Result<Self, AxumCognitoError> {
+        // Checked before the JWKS is fetched, whose url derives from the same issuer
+        let mut key_set = CognitoKeySet::new(&pool_config.endpoint_url(), &pool_config.pool_id);
+        if let Some(max_jwks_size) = pool_config.max_jwks_size {
+            key_set.set_max_jwks_size(max_jwks_size);
+        }
+        key_set.check_issuer().await?;
+        let validator = Self::build(token_type, pool_config, None, true).await?;
+        if !validator.pool_config.local_public_keys.is_empty() {
+            validator.key_set.fetch().await?;
+        }
+        if validator.key_set.fetched_key_count() == 0 {
+            return Err(AxumCognitoError::InvalidConfiguration(format!(
+                "JWKS of `{}` holds no usable key, check the pool id and region",
+                validator.issuer()
+            )));
+        }
+        Ok(validator)
+    }
+
+    /// Create a new `CognitoValidator` fetching the JWKS of the pool using a custom fetcher
+    ///
+    /// # Arguments
+    /// * `token_type` - type of token to validate, one of `ID` or `Access`
+    /// * `pool_config` - Cognito pool and clients to accept tokens from
+    /// * `jwks_fetcher` - fetcher used instead of the built-in HTTP fetch of the JWKS
+    ///
+    /// # Returns
+    /// a new `CognitoValidator`
+    ///
+    /// # Errors
+    /// Returns an error if the `CognitoValidator` cannot be created
+    pub async fn from_pool_config_with_jwks_fetcher(
+        token_type: OAuthTokenType,
+        pool_config: PoolConfig,
+        jwks_fetcher: Arc<dyn JwksFetcher>,
+    ) -> Result<Self, AxumCognitoError> {
+        Self::build(token_type, pool_config, Some(jwks_fetcher), true).await
+    }
+
+    /// Build a validator, prefetching the JWKS unless `prefetch` is `false` or local public keys
+    /// are registered
+    async fn build(
+        token_type: OAuthTokenType,
+        pool_config: PoolConfig,
+        jwks_fetcher: Option<Arc<dyn JwksFetcher>>,
+        prefetch: bool,
+    ) -> Result<Self, AxumCognitoError> {
+        let mut key_set = CognitoKeySet::new(&pool_config.endpoint_url(), &pool_config.pool_id);
+        if let Some(jwks_fetcher) = jwks_fetcher {
+            key_set.set_fetcher(jwks_fetcher);
+        }
+        if let Some(max_jwks_size) = pool_config.max_jwks_size {
+            key_set.set_max_jwks_size(max_jwks_size);
+        }
+        if prefetch && pool_config.local_public_keys.is_empty() {
+            key_set.fetch().await?;
+        }
+        for local_public_key in &pool_config.local_public_keys {
+            key_set.add_local_key(
+                &local_public_key.pem,
+                &local_public_key.kid,
+                &local_public_key.alg,
+            )?;
+        }
+
+        let token_verifier =
+            build_token_verifier(token_type, &pool_config, &key_set, pool_config.leeway)?;
+        let startup_grace = match pool_config.startup_grace {
+            Some(startup_grace) => {
+                let leeway = u32::try_from(startup_grace.as_secs()).unwrap_or(u32::MAX);
+                Some(StartupGrace {
+                    until: Instant::now() + startup_grace,
+                    token_verifier: build_token_verifier(
+                        token_type,
+                        &pool_config,
+                        &key_set,
+                        leeway.max(pool_config.leeway),
+                    )?,
+                })
+            }
+            None => None,
         };
 
         Ok(Self {
+            token_type,
+            pool_config,
             key_set,
             token_verifier,
+            startup_grace,
+            clock_skew_warned: Arc::new(AtomicBool::new(false)),
+            deprecated_client_ids: HashSet::new(),
+            deprecation_warnings: Arc::new(Mutex::new(HashMap::new())),
+            require_sub: true,
+            max_token_age: None,
+            allowed_typ: None,
+            claims_cache: None,
+            lenient_claims: false,
             phantom_data: PhantomData,
         })
     }
 
+    /// Cognito pool and clients this validator accepts tokens from
+    #[must_use]
+    pub fn pool_config(&self) -> &PoolConfig {
+        &self.pool_config
+    }
+
+    /// Time since the JWKS of the pool was last fetched successfully, e.g. to alert when
+    /// refreshing the keys stalls
+    ///
+    /// `None` if the JWKS was never fetched, which only happens when local public keys are
+    /// registered
+    #[must_use]
+    pub fn jwks_age(&self) -> Option<Duration> {
+        self.key_set.age()
+    }
+
+    /// Issuer of the tokens this validator accepts
+    pub(crate) fn issuer(&self) -> &str {
+        self.key_set.issuer()
+    }
+
+    /// Minimum size, in bits, of the modulus of the RSA keys this validator accepts tokens signed
+    /// with
+    pub(crate) fn min_rsa_bits(&self) -> Option<usize> {
+        self.key_set.min_rsa_bits()
+    }
+
+    /// Type of the tokens this validator accepts
+    #[must_use]
+    pub fn token_type(&self) -> OAuthTokenType {
+        self.token_type
+    }
+
+    /// Set whether tokens without a non-empty `sub` claim are rejected, enabled by default
+    #[must_use]
+    pub fn with_require_sub(mut self, require_sub: bool) -> Self {
+        self.require_sub = require_sub;
+        self
+    }
+
+    /// Set whether claims failing to deserialize are dropped rather than failing the whole
+    /// verification, disabled by default
+    ///
+    /// Each claim failing to deserialize is logged and dropped before the user claims are
+    /// deserialized again, so optional or defaulted fields of mismatched types are left out while
+    /// the rest of the claims are filled. Trades strictness for resilience, a required field
+    /// failing to deserialize still fails the verification.
+    #[must_use]
+    pub fn with_lenient_claims(mut self, lenient_claims: bool) -> Self {
+        self.lenient_claims = lenient_claims;
+        self
+    }
+
+    /// Reject tokens issued more than `max_token_age` ago, whatever their expiry
+    ///
+    /// The age is computed from the `iat` claim, tokens without one are rejected
+    #[must_use]
+    pub fn with_max_token_age(mut self, max_token_age: Duration) -> Self {
+        self.max_token_age = Some(max_token_age);
+        self
+    }
+
+    /// Only accept tokens whose `typ` header is a standard value for the token type
+    ///
+    /// `JWT` and `at+jwt` are accepted for access tokens and `JWT` for ID tokens, see
+    /// `with_allowed_typ` to accept other values
+    #[must_use]
+    pub fn with_typ_check(self) -> Self {
+        let allowed_typ: &[&str] = match self.token_type {
+            OAuthTokenType::Id => &["JWT"],
+            OAuthTokenType::Access => &["JWT", "at+jwt"],
+        };
+        self.with_allowed_typ(allowed_typ)
+    }
+
+    /// Only accept tokens whose `typ` header is one of `allowed_typ`, compared case-insensitively
+    ///
+    /// Tokens without a `typ` header are still accepted as Cognito does not set it
+    #[must_use]
+    pub fn with_allowed_typ(mut self, allowed_typ: &[&str]) -> Self {
+        self.allowed_typ = Some(allowed_typ.iter().map(ToString::to_string).collect());
+        self
+    }
+
+    /// Cache the claims of up to `max_entries` verified tokens until they expire
+    ///
+    /// Tokens found in the cache skip signature verification as long as their signing key is
+    /// still in the key set, the other checks of the validator still apply. Once the key is
+    /// rotated out and its rotation grace period has elapsed, its tokens are verified again and
+    /// rejected. The cache is keyed by the SHA-256 digest of the tokens, not the tokens.
+    #[must_use]
+    pub fn with_claims_cache(mut self, max_entries: usize) -> Self {
+        self.claims_cache = Some(ClaimsCache::new(max_entries));
+        self
+    }
+
+    /// Set the minimum time between two fetches of the JWKS, one minute by default
+    ///
+    /// The JWKS is fetched again when a token is signed by an unknown key or its signature fails
+    /// verification, this throttles those fetches. Concurrent verifications needing a fetch always
+    /// share a single one.
+    ///
+    /// The interval is measured from the last fetch, including the one made as the validator is
+    /// created: tokens signed by a key rotated within the interval are rejected until it elapses.
+    /// Use `notify_rotation` to pick up a known rotation straight away.
+    ///
+    /// Failed fetches count as fetches, and the interval doubles with each fetch failing in a row,
+    /// up to 15 minutes, so that an unavailable JWKS endpoint is not hammered.
+    #[must_use]
+    pub fn with_min_jwks_fetch_interval(mut self, min_fetch_interval: Duration) -> Self {
+        self.key_set.set_min_fetch_interval(min_fetch_interval);
+        self
+    }
+
+    /// Set the time keys removed from the JWKS are still accepted, 5 minutes by default
+    ///
+    /// Applies whenever the JWKS is fetched, whether the rotation was notified with
+    /// `notify_rotation` or picked up when verifying a token signed by an unknown key
+    #[must_use]
+    pub fn with_rotation_grace(mut self, rotation_grace: Duration) -> Self {
+        self.key_set.set_rotation_grace(rotation_grace);
+        self
+    }
+
+    /// Refresh the keys of the pool straight away, e.g. when notified of a key rotation by a
+    /// webhook
+    ///
+    /// The JWKS is always fetched again, regardless of the minimum fetch interval or of a fetch
+    /// already in flight. Keys no longer in the JWKS still verify tokens during the rotation grace
+    /// period, so tokens signed before the rotation keep working until clients refresh them.
+    ///
+    /// # Errors
+    /// Returns an `AxumCognitoError` if the JWKS cannot be fetched, the previous keys are then
+    /// kept as they are
+    pub async fn notify_rotation(&self) -> Result<(), AxumCognitoError> {
+        self.key_set.rotate().await
+    }
+
+    /// Reject tokens signed with an RSA key whose modulus is smaller than `min_rsa_bits`
+    ///
+    /// Guards against weak keys served by a compromised or misconfigured JWKS, e.g. `2048`
+    #[must_use]
+    pub fn with_min_rsa_bits(mut self, min_rsa_bits: usize) -> Self {
+        self.key_set.set_min_rsa_bits(min_rsa_bits);
+        self
+    }
+
+    /// Warn about verified tokens issued to the deprecated client `client_id`, e.g. to track the
+    /// remaining usage of a client being retired
+    ///
+    /// Tokens of the client are still accepted, as long as it is one of the client ids of the
+    /// pool, but each one logs a warning, at most once a minute per client
+    #[must_use]
+    pub fn deprecate_client_id(mut self, client_id: &str) -> Self {
+        self.deprecated_client_ids.insert(client_id.to_string());
+        self
+    }
+
     /// Validate a token and return the user claims
     ///
+    /// When the token is signed by an unknown key, or its signature does not match the cached
+    /// key, the JWKS is fetched again and the token verified once more, as the keys may have been
+    /// rotated. Other failures, e.g. an expired token, are rejected straight away. The JWKS is
+    /// fetched again at most once every minimum JWKS fetch interval, so a rotation within the
+    /// interval following the last fetch is only picked up once it elapses, see
+    /// `with_min_jwks_fetch_interval` and `notify_rotation`.
+    ///
     /// # Arguments
     /// * `token` - token to validate
     ///
@@ -78,12 +654,1193 @@ where
     /// # Errors
     /// returns an error if the user claims cannot be deserialized
     pub async fn validate_token(&self, token: &str) -> Result<Option<UC>, AxumCognitoError> {
-        let verification = self.key_set.verify(token, &self.token_verifier).await;
-        if let Ok(claims) = verification {
-            let user_claims: UC = serde_json::from_value(claims)?;
-            Ok(Some(user_claims))
+        Ok(self
+            .verify_token(token)
+            .await?
+            .map(|verified_token| verified_token.claims))
+    }
+
+    /// Verify a token and return its user claims, or the response rejecting the request
+    ///
+    /// For handlers verifying tokens themselves rather than behind a `CognitoAuthLayer`, the
+    /// rejection can be returned straight to axum, e.g.
+    /// `let claims = validator.verify_or_response(token).await?;`
+    ///
+    /// # Arguments
+    /// * `token` - token to verify
+    ///
+    /// # Errors
+    /// Returns a 401 with a `Bearer` challenge of the `api` realm if the token is rejected, or
+    /// its user claims cannot be deserialized
+    pub async fn verify_or_response(&self, token: &str) -> Result<UC, Response> {
+        match self.verify_token(token).await {
+            Ok(Some(verified_token)) => Ok(verified_token.claims),
+            Ok(None) => Err(create_unauthorized_response(
+                DEFAULT_REALM,
+                Some(INVALID_TOKEN),
+            )),
+            Err(error) => {
+                tracing::debug!("Rejected a token whose claims cannot be deserialized: {error}");
+                Err(create_unauthorized_response(
+                    DEFAULT_REALM,
+                    Some(INVALID_TOKEN),
+                ))
+            }
+        }
+    }
+
+    /// Verify a token and return its user claims together with its expiry and raw claims
+    ///
+    /// Tokens without a numeric `exp` claim are rejected, floating-point timestamps are truncated
+    /// to seconds
+    ///
+    /// # Arguments
+    /// * `token` - token to verify
+    ///
+    /// # Returns
+    /// The verified token, or `None` if the token is rejected
+    ///
+    /// # Errors
+    /// returns an error if the user claims cannot be deserialized
+    pub async fn verify_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<VerifiedToken<UC>>, AxumCognitoError> {
+        let Some(raw) = self.verify_claims(token).await else {
+            return Ok(None);
+        };
+
+        let Some(exp) = raw.get("exp").and_then(Value::as_i64) else {
+            return Ok(None);
+        };
+
+        let claims = self.deserialize_claims(&raw)?;
+        Ok(Some(VerifiedToken { claims, exp, raw }))
+    }
+
+    /// Deserialize the user claims, dropping the claims failing to deserialize when lenient
+    fn deserialize_claims(&self, raw: &Value) -> Result<UC, AxumCognitoError> {
+        if !self.lenient_claims {
+            return Ok(UC::deserialize(raw)?);
+        }
+
+        let mut claims = raw.clone();
+        loop {
+            let error = match serde_path_to_error::deserialize(&claims) {
+                Ok(user_claims) => return Ok(user_claims),
+                Err(error) => error,
+            };
+            let claim = match error.path().iter().next() {
+                Some(serde_path_to_error::Segment::Map { key }) => key.clone(),
+                _ => return Err(error.into_inner().into()),
+            };
+            if claims
+                .as_object_mut()
+                .and_then(|claims| claims.remove(&claim))
+                .is_none()
+            {
+                return Err(error.into_inner().into());
+            }
+            tracing::warn!(
+                claim,
+                error = %error.inner(),
+                "Dropping claim failing to deserialize"
+            );
+        }
+    }
+
+    /// Validate an ID token issued together with an access token and return the user claims
+    ///
+    /// On top of the usual validation the `at_hash` claim of the ID token must match the access
+    /// token, as described by the OIDC authorization code flow
+    ///
+    /// # Arguments
+    /// * `token` - ID token to validate
+    /// * `access_token` - access token issued together with the ID token
+    ///
+    /// # Returns
+    /// User claims extracted from the provided token
+    ///
+    /// # Errors
+    /// returns an error if the user claims cannot be deserialized
+    pub async fn validate_token_with_at_hash(
+        &self,
+        token: &str,
+        access_token: &str,
+    ) -> Result<Option<UC>, AxumCognitoError> {
+        let Some(claims) = self.verify_claims(token).await else {
+            return Ok(None);
+        };
+
+        let header = jwt::raw::decode_header_only(token)?;
+        if !token_hash_matches(&header, &claims, "at_hash", access_token) {
+            return Ok(None);
+        }
+
+        Ok(Some(self.deserialize_claims(&claims)?))
+    }
+
+    /// Cognito pool and client that issued a token verified by this validator
+    pub(crate) fn auth_context(&self, claims: &Value) -> AuthContext {
+        AuthContext {
+            pool_id: self.pool_config.pool_id.clone(),
+            region: self.pool_config.region.clone(),
+            client_id: claims
+                .get(self.token_type.client_id_claim())
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+
+    /// Verify the signature and standard claims of a token, falling back to the lenient
+    /// verifier during the startup grace period
+    async fn verify_signature_and_claims(&self, token: &str) -> Option<Value> {
+        let verification = match self.key_set.verify(token, &self.token_verifier).await {
+            Err(AxumCognitoError::Jsonwebtokens(_)) if self.in_startup_grace() => {
+                let startup_grace = self.startup_grace.as_ref()?;
+                self.key_set
+                    .verify(token, &startup_grace.token_verifier)
+                    .await
+            }
+            verification => verification,
+        };
+
+        match verification {
+            Ok(claims) => Some(claims),
+            Err(error) => {
+                let header = unverified_header(token).unwrap_or_default();
+                tracing::debug!(
+                    kid = header.kid,
+                    alg = header.alg,
+                    %error,
+                    "Token verification failed"
+                );
+                self.warn_on_clock_skew(token);
+                None
+            }
+        }
+    }
+
+    fn in_startup_grace(&self) -> bool {
+        self.startup_grace
+            .as_ref()
+            .is_some_and(|startup_grace| Instant::now() < startup_grace.until)
+    }
+
+    /// Warn, once, when a rejected token was issued far in the future, which suggests the system
+    /// clock is wrong rather than the token
+    fn warn_on_clock_skew(&self, token: &str) {
+        let Ok(token_data) = jwt::raw::decode_only(token) else {
+            return;
+        };
+        let Some(iat) = token_data.claims.get("iat").and_then(numeric_timestamp) else {
+            return;
+        };
+        let now = unix_timestamp();
+        if iat > now + CLOCK_SKEW_WARNING_THRESHOLD.as_secs()
+            && !self.clock_skew_warned.swap(true, Ordering::Relaxed)
+        {
+            tracing::warn!(
+                iat,
+                now,
+                "Rejected a token issued in the future, the system clock may be wrong"
+            );
+        }
+    }
+
+    /// Warn, at most once every warning interval per client, when a verified token was issued to
+    /// a deprecated client
+    fn warn_on_deprecated_client(&self, claims: &Value) {
+        let Some(client_id) = claims
+            .get(self.token_type.client_id_claim())
+            .and_then(Value::as_str)
+        else {
+            return;
+        };
+        if !self.deprecated_client_ids.contains(client_id) {
+            return;
+        }
+        let mut deprecation_warnings = self
+            .deprecation_warnings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let last_warning = deprecation_warnings.get(client_id);
+        if last_warning
+            .is_none_or(|last_warning| last_warning.elapsed() >= DEPRECATED_CLIENT_WARNING_INTERVAL)
+        {
+            deprecation_warnings.insert(client_id.to_string(), Instant::now());
+            tracing::warn!(client_id, "Accepted a token issued to a deprecated client");
+        }
+    }
+
+    /// Whether the key that signed a token is still in the key set, so that its cached claims can
+    /// be trusted
+    fn is_signing_key_known(&self, token: &str) -> bool {
+        jwt::raw::decode_header_only(token)
+            .ok()
+            .and_then(|header| {
+                header
+                    .get("kid")?
+                    .as_str()
+                    .map(|kid| self.key_set.has_key(kid))
+            })
+            .unwrap_or(false)
+    }
+
+    /// Verify a token and return its raw claims, or `None` if the token is rejected
+    pub(crate) async fn verify_claims(&self, token: &str) -> Option<Value> {
+        if !self.is_typ_allowed(token) {
+            return None;
+        }
+
+        let cached_claims = self
+            .claims_cache
+            .as_ref()
+            .and_then(|claims_cache| claims_cache.get(token))
+            .filter(|_| self.is_signing_key_known(token));
+        let claims = if let Some(claims) = cached_claims {
+            claims
         } else {
-            Ok(None)
+            let claims = self.verify_signature_and_claims(token).await?;
+            if let Some(claims_cache) = &self.claims_cache {
+                claims_cache.insert(token, &claims);
+            }
+            claims
+        };
+
+        if !self.are_claims_allowed(&claims) {
+            return None;
+        }
+
+        self.warn_on_deprecated_client(&claims);
+        Some(claims)
+    }
+
+    /// Check a token whose signature was verified by another validator of the same pool
+    ///
+    /// The checks of this validator that follow the signature, i.e. the `typ` allowlist, the `sub`
+    /// requirement, the audience policy and the maximum age, are run again on the claims, as the
+    /// other validator may have been configured less strictly
+    pub(crate) fn accepts_verified_claims(&self, token: &str, claims: &Value) -> bool {
+        self.is_typ_allowed(token) && self.are_claims_allowed(claims)
+    }
+
+    /// Whether the `typ` header of a token is absent or in the allowlist, when there is one
+    fn is_typ_allowed(&self, token: &str) -> bool {
+        let Some(allowed_typ) = &self.allowed_typ else {
+            return true;
+        };
+        let Ok(header) = unverified_header(token) else {
+            return false;
+        };
+        header.typ.is_none_or(|typ| {
+            allowed_typ
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(&typ))
+        })
+    }
+
+    /// Whether verified claims satisfy the `sub` requirement, the audience policy and the maximum
+    /// age of this validator
+    fn are_claims_allowed(&self, claims: &Value) -> bool {
+        if self.require_sub && !has_subject(claims) {
+            return false;
         }
+
+        // Client ids that must be present are also checked by the token verifier, which has no
+        // support for claims that are optional
+        let client_id = claims.get(self.token_type.client_id_claim());
+        let is_accepted_client = |client_id: &Value| {
+            client_id.as_str().is_some_and(|client_id| {
+                self.pool_config.client_ids.iter().any(|id| id == client_id)
+            })
+        };
+        let audience_allowed = match self.pool_config.audience_policy {
+            AudiencePolicy::Required => client_id.is_some_and(is_accepted_client),
+            AudiencePolicy::OptionalIfPresent => client_id.is_none_or(is_accepted_client),
+            AudiencePolicy::Ignored => true,
+        };
+        if !audience_allowed {
+            return false;
+        }
+
+        self.max_token_age
+            .is_none_or(|max_token_age| issued_within(claims, max_token_age))
+    }
+}
+
+fn build_token_verifier(
+    token_type: OAuthTokenType,
+    pool_config: &PoolConfig,
+    key_set: &CognitoKeySet,
+    leeway: u32,
+) -> Result<jwt::Verifier, AxumCognitoError> {
+    let client_ids: Vec<&str> = pool_config.client_ids.iter().map(String::as_str).collect();
+    let mut verifier_builder = jwt::Verifier::create();
+    // Mirrors and proxies sometimes add a trailing slash to the issuer
+    let issuer = key_set.issuer().trim_end_matches('/').to_string();
+    verifier_builder
+        .claim_callback("iss", move |iss| {
+            iss.as_str()
+                .is_some_and(|iss| iss.trim_end_matches('/') == issuer)
+        })
+        .leeway(leeway);
+    verifier_builder.string_equals("token_use", token_type.token_use());
+    if pool_config.audience_policy == AudiencePolicy::Required {
+        verifier_builder.string_equals_one_of(token_type.client_id_claim(), &client_ids);
+    }
+    Ok(verifier_builder.build()?)
+}
+
+fn issued_within(claims: &Value, max_token_age: Duration) -> bool {
+    claims
+        .get("iat")
+        .and_then(Value::as_u64)
+        .is_some_and(|iat| unix_timestamp().saturating_sub(iat) <= max_token_age.as_secs())
+}
+
+/// Check an OIDC `at_hash` or `c_hash` claim against the value it is the hash of
+///
+/// The hash is the base64url encoded left half of the digest of the value, using the hash
+/// algorithm of the token's signature
+fn token_hash_matches(header: &Value, claims: &Value, claim: &str, value: &str) -> bool {
+    let algorithm = match header.get("alg").and_then(Value::as_str) {
+        Some(alg) if alg.ends_with("256") => &digest::SHA256,
+        Some(alg) if alg.ends_with("384") => &digest::SHA384,
+        Some(alg) if alg.ends_with("512") => &digest::SHA512,
+        _ => return false,
+    };
+    let digest = digest::digest(algorithm, value.as_bytes());
+    let left_half = &digest.as_ref()[..digest.as_ref().len() / 2];
+
+    claims.get(claim).and_then(Value::as_str) == Some(URL_SAFE_NO_PAD.encode(left_half).as_str())
+}
+
+/// Current time as a unix timestamp in seconds
+pub(crate) fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Read a numeric unix timestamp, integer or floating-point, truncated to seconds
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub(crate) fn numeric_timestamp(value: &Value) -> Option<u64> {
+    value.as_u64().or_else(|| {
+        value
+            .as_f64()
+            .filter(|timestamp| timestamp.is_finite() && *timestamp >= 0.0)
+            .map(|timestamp| timestamp as u64)
+    })
+}
+
+fn has_subject(claims: &Value) -> bool {
+    claims
+        .get("sub")
+        .and_then(Value::as_str)
+        .is_some_and(|sub| !sub.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::Json;
+    use http::{header, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{
+        access_claims, id_claims, id_validator, issuer, jwk, jwks, pool_config, rotated_jwks,
+        serve, serve_pool, sign, sign_with, validator_with, MockJwks, CLIENT_ID, EC_PRIVATE_KEY,
+        EC_PUBLIC_KEY, KID, POOL_ID, REGION, ROTATED_RSA_MODULUS, ROTATED_RSA_PRIVATE_KEY,
+        RSA_PRIVATE_KEY, RSA_PUBLIC_KEY,
+    };
+
+    /// Token signed by the rotated key but still using the key id of the previous key
+    fn token_of_key_rotated_under_same_kid() -> String {
+        sign_with(
+            &id_claims(),
+            &json!({ "alg": "RS256", "kid": KID }),
+            jwt::AlgorithmID::RS256,
+            ROTATED_RSA_PRIVATE_KEY,
+        )
+    }
+
+    fn jwks_of_key_rotated_under_same_kid() -> Value {
+        json!({ "keys": [jwk(KID, "RS256", ROTATED_RSA_MODULUS)] })
+    }
+
+    #[tokio::test]
+    async fn token_of_a_key_rotated_under_the_same_kid_verifies_after_a_refresh() {
+        let mock_jwks = MockJwks::new(jwks());
+        let validator = validator_with(OAuthTokenType::Id, pool_config(), mock_jwks.clone())
+            .await
+            .with_min_jwks_fetch_interval(Duration::ZERO);
+        mock_jwks.set(jwks_of_key_rotated_under_same_kid());
+
+        let claims = validator
+            .validate_token(&token_of_key_rotated_under_same_kid())
+            .await
+            .unwrap();
+
+        assert!(claims.is_some());
+        assert_eq!(mock_jwks.fetches(), 2);
+    }
+
+    #[tokio::test]
+    async fn signature_failure_is_not_refreshed_within_the_minimum_fetch_interval() {
+        let mock_jwks = MockJwks::new(jwks());
+        let validator = validator_with(OAuthTokenType::Id, pool_config(), mock_jwks.clone()).await;
+        mock_jwks.set(jwks_of_key_rotated_under_same_kid());
+
+        let claims = validator
+            .validate_token(&token_of_key_rotated_under_same_kid())
+            .await
+            .unwrap();
+
+        assert!(claims.is_none());
+        assert_eq!(mock_jwks.fetches(), 1);
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_rejected_without_a_refresh() {
+        let mock_jwks = MockJwks::new(jwks());
+        let validator = validator_with(OAuthTokenType::Id, pool_config(), mock_jwks.clone())
+            .await
+            .with_min_jwks_fetch_interval(Duration::ZERO);
+        let mut claims = id_claims();
+        claims["exp"] = json!(unix_timestamp() - 60);
+
+        let claims = validator.validate_token(&sign(&claims)).await.unwrap();
+
+        assert!(claims.is_none());
+        assert_eq!(mock_jwks.fetches(), 1);
+    }
+
+    #[tokio::test]
+    async fn checked_constructor_accepts_a_reachable_pool_with_keys() {
+        let endpoint = serve_pool(Json(jwks())).await;
+
+        let validator = CognitoValidator::<Value>::new_checked(
+            OAuthTokenType::Id,
+            pool_config().with_endpoint(&endpoint),
+        )
+        .await;
+
+        assert_eq!(validator.unwrap().issuer(), format!("{endpoint}/{POOL_ID}"));
+    }
+
+    #[tokio::test]
+    async fn checked_constructor_rejects_an_empty_jwks() {
+        let endpoint = serve_pool(Json(json!({ "keys": [] }))).await;
+
+        let validator = CognitoValidator::<Value>::new_checked(
+            OAuthTokenType::Id,
+            pool_config().with_endpoint(&endpoint),
+        )
+        .await;
+
+        assert!(matches!(
+            validator,
+            Err(AxumCognitoError::InvalidConfiguration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn checked_constructor_rejects_an_unreachable_issuer() {
+        // Serves nothing, as if the pool id was mistyped
+        let endpoint = serve(axum::Router::new()).await;
+
+        let validator = CognitoValidator::<Value>::new_checked(
+            OAuthTokenType::Id,
+            pool_config().with_endpoint(&endpoint),
+        )
+        .await;
+
+        assert!(matches!(
+            validator,
+            Err(AxumCognitoError::InvalidConfiguration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn checked_constructor_rejects_a_discovery_document_larger_than_the_maximum_size() {
+        let router = axum::Router::new().route(
+            &format!("/{POOL_ID}/.well-known/openid-configuration"),
+            axum::routing::get(|| async { " ".repeat(1024) }),
+        );
+        let endpoint = serve(router).await;
+
+        let validator = CognitoValidator::<Value>::new_checked(
+            OAuthTokenType::Id,
+            pool_config()
+                .with_endpoint(&endpoint)
+                .with_max_jwks_size(512),
+        )
+        .await;
+
+        assert!(matches!(
+            validator,
+            Err(AxumCognitoError::InvalidConfiguration(message))
+                if message.contains("larger than the maximum")
+        ));
+    }
+
+    #[tokio::test]
+    async fn token_without_sub_is_rejected_by_default() {
+        let validator = id_validator().await;
+        let mut claims = id_claims();
+        claims.as_object_mut().unwrap().remove("sub");
+
+        assert_eq!(
+            validator.validate_token(&sign(&claims)).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn token_with_an_empty_sub_is_rejected_by_default() {
+        let validator = id_validator().await;
+        let mut claims = id_claims();
+        claims["sub"] = json!("");
+
+        assert_eq!(
+            validator.validate_token(&sign(&claims)).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn token_without_sub_is_accepted_when_not_required() {
+        let validator = id_validator().await.with_require_sub(false);
+        let mut claims = id_claims();
+        claims.as_object_mut().unwrap().remove("sub");
+
+        assert!(validator
+            .validate_token(&sign(&claims))
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    /// `at_hash` of an access token signed with RS256
+    fn at_hash(access_token: &str) -> String {
+        let digest = digest::digest(&digest::SHA256, access_token.as_bytes());
+        URL_SAFE_NO_PAD.encode(&digest.as_ref()[..16])
+    }
+
+    #[tokio::test]
+    async fn id_token_whose_at_hash_matches_the_access_token_is_accepted() {
+        let validator = id_validator().await;
+        let access_token = sign(&access_claims());
+        let mut claims = id_claims();
+        claims["at_hash"] = json!(at_hash(&access_token));
+
+        let user_claims = validator
+            .validate_token_with_at_hash(&sign(&claims), &access_token)
+            .await
+            .unwrap();
+
+        assert!(user_claims.is_some());
+    }
+
+    #[tokio::test]
+    async fn id_token_whose_at_hash_matches_another_access_token_is_rejected() {
+        let validator = id_validator().await;
+        let access_token = sign(&access_claims());
+        let mut claims = id_claims();
+        claims["at_hash"] = json!(at_hash("another-access-token"));
+
+        let user_claims = validator
+            .validate_token_with_at_hash(&sign(&claims), &access_token)
+            .await
+            .unwrap();
+
+        assert_eq!(user_claims, None);
+    }
+
+    #[tokio::test]
+    async fn id_token_without_at_hash_is_rejected() {
+        let validator = id_validator().await;
+
+        let user_claims = validator
+            .validate_token_with_at_hash(&sign(&id_claims()), &sign(&access_claims()))
+            .await
+            .unwrap();
+
+        assert_eq!(user_claims, None);
+    }
+
+    #[tokio::test]
+    async fn token_older_than_the_maximum_age_is_rejected_before_it_expires() {
+        let validator = id_validator()
+            .await
+            .with_max_token_age(Duration::from_secs(600));
+        let mut old_claims = id_claims();
+        old_claims["iat"] = json!(unix_timestamp() - 900);
+        let mut claims_without_iat = id_claims();
+        claims_without_iat.as_object_mut().unwrap().remove("iat");
+
+        let old = validator.validate_token(&sign(&old_claims)).await.unwrap();
+        let without_iat = validator
+            .validate_token(&sign(&claims_without_iat))
+            .await
+            .unwrap();
+        let recent = validator.validate_token(&sign(&id_claims())).await.unwrap();
+
+        assert_eq!(old, None);
+        assert_eq!(without_iat, None);
+        assert!(recent.is_some());
+    }
+
+    /// Token of `id_claims` signed by the test key with the `typ` header `typ`
+    fn sign_with_typ(typ: &str) -> String {
+        sign_with(
+            &id_claims(),
+            &json!({ "alg": "RS256", "kid": KID, "typ": typ }),
+            jwt::AlgorithmID::RS256,
+            RSA_PRIVATE_KEY,
+        )
+    }
+
+    #[tokio::test]
+    async fn typ_check_only_accepts_standard_typ_values() {
+        let validator = id_validator().await.with_typ_check();
+
+        let jwt = validator
+            .validate_token(&sign_with_typ("JWT"))
+            .await
+            .unwrap();
+        let at_jwt = validator
+            .validate_token(&sign_with_typ("at+jwt"))
+            .await
+            .unwrap();
+        let without_typ = validator.validate_token(&sign(&id_claims())).await.unwrap();
+
+        assert!(jwt.is_some());
+        assert_eq!(at_jwt, None);
+        assert!(without_typ.is_some());
+    }
+
+    #[tokio::test]
+    async fn allowed_typ_values_are_compared_case_insensitively() {
+        let validator = id_validator().await.with_allowed_typ(&["application/jwt"]);
+
+        let allowed = validator
+            .validate_token(&sign_with_typ("Application/JWT"))
+            .await
+            .unwrap();
+        let other = validator
+            .validate_token(&sign_with_typ("JWT"))
+            .await
+            .unwrap();
+
+        assert!(allowed.is_some());
+        assert_eq!(other, None);
+    }
+
+    #[tokio::test]
+    async fn verified_token_holds_the_typed_claims_the_expiry_and_the_raw_claims() {
+        #[derive(Debug, serde::Deserialize)]
+        struct UserClaims {
+            #[serde(rename = "cognito:username")]
+            username: String,
+        }
+        let validator = CognitoValidator::<UserClaims>::from_pool_config_with_jwks_fetcher(
+            OAuthTokenType::Id,
+            pool_config(),
+            MockJwks::new(jwks()),
+        )
+        .await
+        .unwrap();
+        let claims = id_claims();
+
+        let verified_token = validator
+            .verify_token(&sign(&claims))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(verified_token.claims.username, "alice");
+        assert_eq!(Some(verified_token.exp), claims["exp"].as_i64());
+        assert_eq!(verified_token.raw, claims);
+    }
+
+    #[tokio::test]
+    async fn pool_leeway_accepts_a_token_expired_within_it() {
+        let strict = id_validator().await;
+        let lenient = validator_with(
+            OAuthTokenType::Id,
+            pool_config().with_leeway(120),
+            MockJwks::new(jwks()),
+        )
+        .await;
+        let mut claims = id_claims();
+        claims["exp"] = json!(unix_timestamp() - 60);
+        let token = sign(&claims);
+
+        assert_eq!(strict.validate_token(&token).await.unwrap(), None);
+        assert!(lenient.validate_token(&token).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn issuer_with_a_trailing_slash_is_accepted() {
+        let validator = id_validator().await;
+        let mut claims = id_claims();
+        claims["iss"] = json!(format!("{}/", issuer()));
+
+        assert!(validator
+            .validate_token(&sign(&claims))
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn issuer_of_another_pool_is_rejected() {
+        let validator = id_validator().await;
+        let mut claims = id_claims();
+        claims["iss"] = json!(format!("{}-other/", issuer()));
+
+        assert_eq!(
+            validator.validate_token(&sign(&claims)).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn startup_grace_tolerates_a_skewed_clock_right_after_creation() {
+        let validator = validator_with(
+            OAuthTokenType::Id,
+            pool_config().with_startup_grace(Duration::from_secs(300)),
+            MockJwks::new(jwks()),
+        )
+        .await;
+        let mut skewed_claims = id_claims();
+        skewed_claims["exp"] = json!(unix_timestamp() - 120);
+        let mut expired_claims = id_claims();
+        expired_claims["exp"] = json!(unix_timestamp() - 600);
+
+        let skewed = validator
+            .validate_token(&sign(&skewed_claims))
+            .await
+            .unwrap();
+        let expired = validator
+            .validate_token(&sign(&expired_claims))
+            .await
+            .unwrap();
+
+        assert!(skewed.is_some());
+        assert_eq!(expired, None);
+    }
+
+    /// Floating-point timestamp three quarters of a second after `timestamp`
+    fn fractional_timestamp(timestamp: u64) -> Value {
+        json!(format!("{timestamp}.75").parse::<f64>().unwrap())
+    }
+
+    #[tokio::test]
+    async fn floating_point_timestamps_are_truncated_to_seconds() {
+        let validator = id_validator()
+            .await
+            .with_max_token_age(Duration::from_secs(600));
+        let now = unix_timestamp();
+        let mut claims = id_claims();
+        claims["iat"] = fractional_timestamp(now - 1);
+        claims["exp"] = fractional_timestamp(now + 3600);
+
+        let verified_token = validator
+            .verify_token(&sign(&claims))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(verified_token.exp, i64::try_from(now + 3600).unwrap());
+        assert_eq!(verified_token.raw["iat"], json!(now - 1));
+    }
+
+    #[tokio::test]
+    async fn floating_point_expiry_in_the_past_is_rejected() {
+        let validator = id_validator().await;
+        let mut claims = id_claims();
+        claims["exp"] = fractional_timestamp(unix_timestamp() - 60);
+
+        assert_eq!(
+            validator.validate_token(&sign(&claims)).await.unwrap(),
+            None
+        );
+    }
+
+    /// Whether a validator with `audience_policy` accepts access tokens with, in turn, the test
+    /// client id, another client id and no client id
+    async fn accepted_client_ids(audience_policy: AudiencePolicy) -> [bool; 3] {
+        let validator = validator_with(
+            OAuthTokenType::Access,
+            pool_config().with_audience_policy(audience_policy),
+            MockJwks::new(jwks()),
+        )
+        .await;
+        let mut other_client_claims = access_claims();
+        other_client_claims["client_id"] = json!("other-client");
+        let mut claims_without_client = access_claims();
+        claims_without_client
+            .as_object_mut()
+            .unwrap()
+            .remove("client_id");
+
+        let mut accepted = [false; 3];
+        for (accepted, claims) in
+            accepted
+                .iter_mut()
+                .zip([access_claims(), other_client_claims, claims_without_client])
+        {
+            *accepted = validator
+                .validate_token(&sign(&claims))
+                .await
+                .unwrap()
+                .is_some();
+        }
+        accepted
+    }
+
+    #[tokio::test]
+    async fn required_audience_must_be_present_and_accepted() {
+        assert_eq!(
+            accepted_client_ids(AudiencePolicy::Required).await,
+            [true, false, false]
+        );
+    }
+
+    #[tokio::test]
+    async fn optional_audience_must_be_accepted_if_present() {
+        assert_eq!(
+            accepted_client_ids(AudiencePolicy::OptionalIfPresent).await,
+            [true, false, true]
+        );
+    }
+
+    #[tokio::test]
+    async fn ignored_audience_is_not_checked() {
+        assert_eq!(
+            accepted_client_ids(AudiencePolicy::Ignored).await,
+            [true, true, true]
+        );
+    }
+
+    #[tokio::test]
+    async fn fips_pool_expects_the_fips_issuer() {
+        // FIPS endpoints of Cognito only exist in US regions
+        let fips_pool_id = "us-gov-west-1_TestPool";
+        let validator = validator_with(
+            OAuthTokenType::Id,
+            PoolConfig::new(&[CLIENT_ID], fips_pool_id, "us-gov-west-1").with_fips(true),
+            MockJwks::new(jwks()),
+        )
+        .await;
+        let fips_issuer =
+            format!("https://cognito-idp-fips.us-gov-west-1.amazonaws.com/{fips_pool_id}");
+        let mut fips_claims = id_claims();
+        fips_claims["iss"] = json!(fips_issuer);
+
+        assert_eq!(validator.issuer(), fips_issuer);
+        assert_eq!(
+            validator.key_set.jwks_url,
+            format!("{fips_issuer}/.well-known/jwks.json")
+        );
+        assert!(validator
+            .validate_token(&sign(&fips_claims))
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            validator.validate_token(&sign(&id_claims())).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn endpoint_takes_precedence_over_fips() {
+        let validator = validator_with(
+            OAuthTokenType::Id,
+            pool_config()
+                .with_fips(true)
+                .with_endpoint("http://localhost:9229/"),
+            MockJwks::new(jwks()),
+        )
+        .await;
+
+        assert_eq!(
+            validator.issuer(),
+            format!("http://localhost:9229/{POOL_ID}")
+        );
+    }
+
+    #[tokio::test]
+    async fn token_signed_by_a_local_ecdsa_key_verifies_without_fetching_the_jwks() {
+        let mock_jwks = MockJwks::new(jwks());
+        let validator = validator_with(
+            OAuthTokenType::Id,
+            pool_config().with_local_public_key(EC_PUBLIC_KEY.as_bytes(), "local-key", "ES256"),
+            mock_jwks.clone(),
+        )
+        .await;
+        let token = sign_with(
+            &id_claims(),
+            &json!({ "alg": "ES256", "kid": "local-key" }),
+            jwt::AlgorithmID::ES256,
+            EC_PRIVATE_KEY,
+        );
+
+        assert!(validator.validate_token(&token).await.unwrap().is_some());
+        assert_eq!(mock_jwks.fetches(), 0);
+        assert_eq!(validator.jwks_age(), None);
+    }
+
+    #[tokio::test]
+    async fn local_rsa_key_verifies_alongside_the_jwks() {
+        let mock_jwks = MockJwks::new(jwks());
+        let validator = validator_with(
+            OAuthTokenType::Id,
+            pool_config().with_local_public_key(RSA_PUBLIC_KEY.as_bytes(), "local-key", "RS256"),
+            mock_jwks.clone(),
+        )
+        .await;
+        let local_token = sign_with(
+            &id_claims(),
+            &json!({ "alg": "RS256", "kid": "local-key" }),
+            jwt::AlgorithmID::RS256,
+            RSA_PRIVATE_KEY,
+        );
+
+        assert!(validator
+            .validate_token(&local_token)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(validator
+            .validate_token(&sign(&id_claims()))
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(mock_jwks.fetches(), 1);
+    }
+
+    #[tokio::test]
+    async fn invalid_local_key_fails_the_creation_of_the_validator() {
+        let validator = CognitoValidator::<Value>::from_pool_config_with_jwks_fetcher(
+            OAuthTokenType::Id,
+            pool_config().with_local_public_key(b"not a key", "local-key", "RS256"),
+            MockJwks::new(jwks()),
+        )
+        .await;
+
+        assert!(validator.is_err());
+    }
+
+    #[tokio::test]
+    async fn jwks_age_is_the_time_since_the_last_fetch() {
+        let validator = id_validator().await;
+        let age_after_creation = validator.jwks_age().unwrap();
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(age_after_creation < Duration::from_secs(1));
+        assert!(validator.jwks_age().unwrap() >= Duration::from_millis(50));
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct ProfileClaims {
+        #[serde(rename = "cognito:username")]
+        username: String,
+        #[serde(default)]
+        age: Option<u32>,
+    }
+
+    async fn profile_validator() -> CognitoValidator<ProfileClaims> {
+        CognitoValidator::from_pool_config_with_jwks_fetcher(
+            OAuthTokenType::Id,
+            pool_config(),
+            MockJwks::new(jwks()),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn lenient_claims_drop_an_optional_claim_of_a_mismatched_type() {
+        let mut claims = id_claims();
+        claims["age"] = json!("forty-two");
+        let token = sign(&claims);
+
+        let strict = profile_validator().await.validate_token(&token).await;
+        let lenient = profile_validator()
+            .await
+            .with_lenient_claims(true)
+            .validate_token(&token)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(strict.is_err());
+        assert_eq!(lenient.username, "alice");
+        assert_eq!(lenient.age, None);
+    }
+
+    #[tokio::test]
+    async fn lenient_claims_still_fail_on_a_required_claim_of_a_mismatched_type() {
+        let mut claims = id_claims();
+        claims["cognito:username"] = json!(42);
+
+        let lenient = profile_validator()
+            .await
+            .with_lenient_claims(true)
+            .validate_token(&sign(&claims))
+            .await;
+
+        assert!(lenient.is_err());
+    }
+
+    #[tokio::test]
+    async fn lenient_claims_apply_to_tokens_validated_with_at_hash() {
+        let access_token = sign(&access_claims());
+        let mut claims = id_claims();
+        claims["at_hash"] = json!(at_hash(&access_token));
+        claims["age"] = json!([42]);
+
+        let lenient = profile_validator()
+            .await
+            .with_lenient_claims(true)
+            .validate_token_with_at_hash(&sign(&claims), &access_token)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(lenient.username, "alice");
+        assert_eq!(lenient.age, None);
+    }
+
+    #[tokio::test]
+    async fn jwks_larger_than_the_maximum_size_of_the_pool_fails_the_creation() {
+        let endpoint = serve_pool(Json(jwks())).await;
+
+        let validator = CognitoValidator::<Value>::from_pool_config(
+            OAuthTokenType::Id,
+            pool_config()
+                .with_endpoint(&endpoint)
+                .with_max_jwks_size(64),
+        )
+        .await;
+
+        assert!(matches!(validator, Err(AxumCognitoError::KeySet(_))));
+    }
+
+    /// Time the deprecated `client_id` was last warned about
+    fn last_deprecation_warning(
+        validator: &CognitoValidator<Value>,
+        client_id: &str,
+    ) -> Option<Instant> {
+        validator
+            .deprecation_warnings
+            .lock()
+            .unwrap()
+            .get(client_id)
+            .copied()
+    }
+
+    #[tokio::test]
+    async fn token_of_a_deprecated_client_is_accepted_and_warned_about_once() {
+        let validator = validator_with(
+            OAuthTokenType::Id,
+            PoolConfig::new(&[CLIENT_ID, "new-client"], POOL_ID, REGION),
+            MockJwks::new(jwks()),
+        )
+        .await
+        .deprecate_client_id(CLIENT_ID);
+        let mut new_client_claims = id_claims();
+        new_client_claims["aud"] = json!("new-client");
+
+        assert!(validator
+            .validate_token(&sign(&new_client_claims))
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(last_deprecation_warning(&validator, CLIENT_ID), None);
+        assert!(validator
+            .validate_token(&sign(&id_claims()))
+            .await
+            .unwrap()
+            .is_some());
+        let first_warning = last_deprecation_warning(&validator, CLIENT_ID).unwrap();
+        assert!(validator
+            .validate_token(&sign(&id_claims()))
+            .await
+            .unwrap()
+            .is_some());
+
+        assert_eq!(
+            last_deprecation_warning(&validator, CLIENT_ID),
+            Some(first_warning)
+        );
+    }
+
+    #[tokio::test]
+    async fn cached_token_of_a_key_rotated_out_is_rejected() {
+        let mock_jwks = MockJwks::new(jwks());
+        let validator = validator_with(OAuthTokenType::Id, pool_config(), mock_jwks.clone())
+            .await
+            .with_claims_cache(10)
+            .with_rotation_grace(Duration::ZERO);
+        let token = sign(&id_claims());
+        assert!(validator.validate_token(&token).await.unwrap().is_some());
+
+        mock_jwks.set(rotated_jwks());
+        validator.notify_rotation().await.unwrap();
+
+        assert_eq!(validator.validate_token(&token).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn client_deprecated_on_a_clone_is_not_deprecated_on_the_original() {
+        let validator = id_validator().await;
+        let _clone = validator.clone().deprecate_client_id(CLIENT_ID);
+
+        assert!(validator
+            .validate_token(&sign(&id_claims()))
+            .await
+            .unwrap()
+            .is_some());
+
+        assert_eq!(last_deprecation_warning(&validator, CLIENT_ID), None);
+    }
+
+    #[tokio::test]
+    async fn verify_or_response_returns_the_claims_of_a_valid_token() {
+        let claims = id_validator()
+            .await
+            .verify_or_response(&sign(&id_claims()))
+            .await
+            .unwrap();
+
+        assert_eq!(claims["cognito:username"], "alice");
+    }
+
+    #[tokio::test]
+    async fn verify_or_response_rejects_an_invalid_token_with_a_401_challenge() {
+        let mut claims = id_claims();
+        claims["exp"] = json!(unix_timestamp() - 60);
+
+        let response = id_validator()
+            .await
+            .verify_or_response(&sign(&claims))
+            .await
+            .unwrap_err();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[header::WWW_AUTHENTICATE],
+            r#"Bearer realm="api", error="invalid_token""#
+        );
     }
 }
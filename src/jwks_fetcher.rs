@@ -0,0 +1,88 @@
+use axum::async_trait;
+use serde_json::Value;
+
+use crate::AxumCognitoError;
+
+/// Fetches the JWKS of a Cognito pool, in place of the built-in HTTP fetch
+///
+/// Useful to add a caching layer, to read the keys from a signed internal mirror or to
+/// authenticate to the JWKS endpoint. The fetcher is called whenever the keys are refreshed, e.g.
+/// when a token is signed by an unknown key, and returns the JWKS document as JSON.
+///
+/// # Example
+/// ```rust
+/// # use axum_cognito::{AxumCognitoError, JwksFetcher};
+/// struct StaticJwks(serde_json::Value);
+///
+/// #[axum::async_trait]
+/// impl JwksFetcher for StaticJwks {
+///     async fn fetch(&self) -> Result<serde_json::Value, AxumCognitoError> {
+///         Ok(self.0.clone())
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait JwksFetcher: Send + Sync {
+    /// Fetch the JWKS document
+    ///
+    /// # Errors
+    /// Returns an `AxumCognitoError` if the JWKS cannot be fetched, e.g. an
+    /// `AxumCognitoError::KeySet`
+    async fn fetch(&self) -> Result<Value, AxumCognitoError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        test_support::{
+            id_claims, jwks, pool_config, rotated_jwks, sign, sign_rotated, validator_with,
+            MockJwks,
+        },
+        CognitoValidator, OAuthTokenType,
+    };
+
+    struct UnavailableJwks;
+
+    #[async_trait]
+    impl JwksFetcher for UnavailableJwks {
+        async fn fetch(&self) -> Result<Value, AxumCognitoError> {
+            Err(AxumCognitoError::KeySet("mirror unavailable".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn keys_are_fetched_with_the_custom_fetcher() {
+        let mock_jwks = MockJwks::new(jwks());
+        let validator = validator_with(OAuthTokenType::Id, pool_config(), mock_jwks.clone())
+            .await
+            .with_min_jwks_fetch_interval(Duration::ZERO);
+
+        let claims = validator.validate_token(&sign(&id_claims())).await.unwrap();
+        mock_jwks.set(json!({ "keys": [jwks()["keys"][0], rotated_jwks()["keys"][0]] }));
+        let rotated_claims = validator
+            .validate_token(&sign_rotated(&id_claims()))
+            .await
+            .unwrap();
+
+        assert!(claims.is_some());
+        assert!(rotated_claims.is_some());
+        assert_eq!(mock_jwks.fetches(), 2);
+    }
+
+    #[tokio::test]
+    async fn error_of_the_custom_fetcher_fails_the_creation_of_the_validator() {
+        let validator = CognitoValidator::<Value>::from_pool_config_with_jwks_fetcher(
+            OAuthTokenType::Id,
+            pool_config(),
+            Arc::new(UnavailableJwks),
+        )
+        .await;
+
+        assert!(matches!(validator, Err(AxumCognitoError::KeySet(_))));
+    }
+}
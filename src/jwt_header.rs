@@ -0,0 +1,74 @@
+use jsonwebtokens as jwt;
+use serde::Deserialize;
+
+use crate::AxumCognitoError;
+
+/// Header of a JWT
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct JwtHeader {
+    /// Id of the key the token is signed with
+    pub kid: Option<String>,
+    /// Algorithm the token is signed with
+    pub alg: Option<String>,
+    /// Media type of the token
+    pub typ: Option<String>,
+}
+
+/// Decode the header of a token **without verifying the token**
+///
+/// Nothing in the returned header can be trusted, it is only meant for diagnostics such as
+/// logging the key id of a token that failed verification
+///
+/// # Arguments
+/// * `token` - token to decode the header of
+///
+/// # Returns
+/// the unverified header of the token
+///
+/// # Errors
+/// Returns an error if the header segment is not base64url encoded JSON
+pub fn unverified_header(token: &str) -> Result<JwtHeader, AxumCognitoError> {
+    let header = jwt::raw::decode_header_only(token)?;
+    Ok(serde_json::from_value(header)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{id_claims, sign, sign_with, KID, ROTATED_RSA_PRIVATE_KEY};
+
+    #[test]
+    fn header_is_decoded_without_verifying_the_token() {
+        // Signed by a key the JWKS does not hold
+        let token = sign_with(
+            &id_claims(),
+            &json!({ "alg": "RS256", "kid": "unknown-key", "typ": "JWT" }),
+            jwt::AlgorithmID::RS256,
+            ROTATED_RSA_PRIVATE_KEY,
+        );
+
+        assert_eq!(
+            unverified_header(&token).unwrap(),
+            JwtHeader {
+                kid: Some("unknown-key".to_string()),
+                alg: Some("RS256".to_string()),
+                typ: Some("JWT".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn absent_header_fields_are_none() {
+        let header = unverified_header(&sign(&id_claims())).unwrap();
+
+        assert_eq!(header.kid.as_deref(), Some(KID));
+        assert_eq!(header.typ, None);
+    }
+
+    #[test]
+    fn token_whose_header_is_not_json_fails_to_decode() {
+        assert!(unverified_header("bm90IGpzb24.e30.c2ln").is_err());
+    }
+}
@@ -3,7 +3,12 @@
 //!
 //!
 //! # Example
-//! ```rust
+//! ```rust,no_run
+//! # use axum_cognito::{AxumCognitoError, CognitoAuthLayer, OAuthTokenType};
+//! # #[derive(Clone, Debug, serde::Deserialize)]
+//! # struct UserClaims {}
+//! # struct Config { cognito_client_id: String, cognito_pool_id: String, cognito_region: String }
+//! # async fn example(olaf_config: Config) -> Result<(), AxumCognitoError> {
 //! let cognito_auth_layer: CognitoAuthLayer<UserClaims> = CognitoAuthLayer::new(
 //!     OAuthTokenType::Id,
 //!     &olaf_config.cognito_client_id,
@@ -11,21 +16,57 @@
 //!     &olaf_config.cognito_region,
 //! )
 //! .await?;
+//! # Ok(())
+//! # }
 //! ```
 #![warn(clippy::pedantic)]
+mod authorization_engine;
+mod authorization_rule;
+mod bearer_token;
+mod claim_trailers;
+mod claims;
+mod claims_cache;
 mod cognito_auth_layer;
+mod cognito_key_set;
+mod cognito_multi_pool_validator;
 mod cognito_validator;
+mod jwks_fetcher;
+mod jwt_header;
+#[cfg(feature = "opentelemetry")]
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod verified_token;
+#[cfg(feature = "warp")]
+mod warp_filter;
+pub use authorization_engine::{AuthorizationEngine, Decision, RequestContext};
+pub use authorization_rule::{AuthorizationRule, GroupNormalizer};
+pub use claims::deserialize_bool_or_string;
 pub use cognito_auth_layer::CognitoAuthLayer;
-pub use cognito_validator::{CognitoValidator, OAuthTokenType};
+pub use cognito_multi_pool_validator::{CognitoMultiPoolValidator, TenantResolver};
+pub use cognito_validator::{AudiencePolicy, CognitoValidator, OAuthTokenType, PoolConfig};
+pub use jwks_fetcher::JwksFetcher;
+pub use jwt_header::{unverified_header, JwtHeader};
 use thiserror::Error;
+pub use verified_token::{AuthContext, CorrelationId, Issuer, Scopes, Username, VerifiedToken};
+#[cfg(feature = "warp")]
+pub use warp_filter::{cognito_auth_filter, CognitoRejection};
 
 /// Axum errors
 #[derive(Error, Debug)]
 pub enum AxumCognitoError {
     #[error("Failed to build key set: `{0}`")]
-    JsonwebtokensCognito(String),
+    KeySet(String),
+    #[error("No key found in the key set for key id `{0}`")]
+    UnknownKeyId(String),
+    #[error("Key `{0}` is smaller than the minimum RSA key size")]
+    KeyTooSmall(String),
+    #[error("Invalid configuration: {0}")]
+    InvalidConfiguration(String),
     #[error(transparent)]
     Jsonwebtokens(#[from] jsonwebtokens::error::Error),
     #[error(transparent)]
     SerdeJson(#[from] serde_json::error::Error),
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
 }
@@ -0,0 +1,177 @@
+use axum::extract::Request;
+use http::StatusCode;
+use opentelemetry::{
+    global::{self, BoxedSpan},
+    trace::{Span, Tracer},
+    Context, KeyValue,
+};
+use serde_json::Value;
+
+use crate::VerifiedToken;
+
+/// `cognito.authorize` span covering the authorization of a request by the layer
+///
+/// The span is a child of the OpenTelemetry context current when the request reaches the layer,
+/// so it is linked into the trace of the request
+pub(crate) struct AuthorizeSpan {
+    span: BoxedSpan,
+}
+
+impl AuthorizeSpan {
+    /// Start the span, `token_use` is `None` when no validator was found for the request
+    pub(crate) fn start(token_use: Option<&'static str>) -> Self {
+        let mut span = global::tracer("axum-cognito")
+            .start_with_context("cognito.authorize", &Context::current());
+        if let Some(token_use) = token_use {
+            span.set_attribute(KeyValue::new("cognito.token_use", token_use));
+        }
+        Self { span }
+    }
+
+    /// Record the outcome of the authorization, and the subject of the token if enabled, and end
+    /// the span
+    ///
+    /// `rejection_status` is the status of the response rejecting the request, `None` when it is
+    /// authorized
+    pub(crate) fn end<UC>(
+        mut self,
+        rejection_status: Option<StatusCode>,
+        request: &Request,
+        include_subject: bool,
+    ) where
+        UC: Send + Sync + 'static,
+    {
+        let outcome = match rejection_status {
+            None => "authorized",
+            Some(status) => match status.as_u16() {
+                400 => "bad_request",
+                401 => "unauthorized",
+                403 => "forbidden",
+                _ => "rejected",
+            },
+        };
+        self.span
+            .set_attribute(KeyValue::new("cognito.outcome", outcome));
+
+        if include_subject {
+            if let Some(sub) = request
+                .extensions()
+                .get::<VerifiedToken<UC>>()
+                .and_then(|verified_token| verified_token.raw.get("sub"))
+                .and_then(Value::as_str)
+            {
+                self.span
+                    .set_attribute(KeyValue::new("enduser.id", sub.to_string()));
+            }
+        }
+        self.span.end();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use axum::{body::Body, routing::get, Router};
+    use http::header;
+    use opentelemetry::{context::FutureExt, trace::TraceContextExt};
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        cognito_validator::unix_timestamp,
+        test_support::{id_claims, id_validator, sign},
+        CognitoAuthLayer,
+    };
+
+    /// Exporter of the spans of the global tracer provider, installed once for every test
+    fn exporter() -> &'static InMemorySpanExporter {
+        static EXPORTER: OnceLock<InMemorySpanExporter> = OnceLock::new();
+        EXPORTER.get_or_init(|| {
+            let exporter = InMemorySpanExporter::default();
+            global::set_tracer_provider(
+                SdkTracerProvider::builder()
+                    .with_simple_exporter(exporter.clone())
+                    .build(),
+            );
+            exporter
+        })
+    }
+
+    /// Send a request with `token` through `layer` within a parent span
+    ///
+    /// # Returns
+    /// the `cognito.authorize` span of the request, a child of the parent span
+    async fn authorize_span(layer: CognitoAuthLayer<Value>, token: &str) -> SpanData {
+        let exporter = exporter();
+        let parent = global::tracer("test").start("request");
+        let parent_span_id = parent.span_context().span_id();
+        let router = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(layer);
+        let request = Request::builder()
+            .uri("/")
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap();
+
+        router
+            .oneshot(request)
+            .with_context(Context::current_with_span(parent))
+            .await
+            .unwrap();
+
+        exporter
+            .get_finished_spans()
+            .unwrap()
+            .into_iter()
+            .find(|span| span.parent_span_id == parent_span_id)
+            .unwrap()
+    }
+
+    fn attribute(span: &SpanData, key: &str) -> Option<String> {
+        span.attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == key)
+            .map(|attribute| attribute.value.to_string())
+    }
+
+    #[tokio::test]
+    async fn authorized_request_records_its_outcome_and_subject() {
+        let claims = id_claims();
+        let layer = CognitoAuthLayer::from_validator(id_validator().await).with_otel_subject();
+
+        let span = authorize_span(layer, &sign(&claims)).await;
+
+        assert_eq!(span.name, "cognito.authorize");
+        assert_eq!(attribute(&span, "cognito.outcome").unwrap(), "authorized");
+        assert_eq!(attribute(&span, "cognito.token_use").unwrap(), "id");
+        assert_eq!(
+            attribute(&span, "enduser.id"),
+            claims["sub"].as_str().map(str::to_string)
+        );
+    }
+
+    #[tokio::test]
+    async fn subject_is_not_recorded_by_default() {
+        let layer = CognitoAuthLayer::from_validator(id_validator().await);
+
+        let span = authorize_span(layer, &sign(&id_claims())).await;
+
+        assert_eq!(attribute(&span, "enduser.id"), None);
+    }
+
+    #[tokio::test]
+    async fn rejected_request_records_its_outcome() {
+        let mut claims = id_claims();
+        claims["exp"] = json!(unix_timestamp() - 60);
+        let layer = CognitoAuthLayer::from_validator(id_validator().await).with_otel_subject();
+
+        let span = authorize_span(layer, &sign(&claims)).await;
+
+        assert_eq!(attribute(&span, "cognito.outcome").unwrap(), "unauthorized");
+        assert_eq!(attribute(&span, "enduser.id"), None);
+    }
+}
@@ -0,0 +1,251 @@
+//! Keys, tokens and JWKS shared by the tests of the crate
+// Not every test module uses every helper, depending on the features enabled
+#![allow(dead_code)]
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use axum::{async_trait, response::IntoResponse, routing::get, Json, Router};
+use http::{header, HeaderMap};
+use jsonwebtokens as jwt;
+use jwt::{Algorithm, AlgorithmID};
+use serde_json::{json, Value};
+
+use crate::{
+    cognito_validator::unix_timestamp, AxumCognitoError, CognitoValidator, JwksFetcher,
+    OAuthTokenType, PoolConfig,
+};
+
+pub(crate) const REGION: &str = "eu-west-1";
+pub(crate) const POOL_ID: &str = "eu-west-1_TestPool";
+pub(crate) const CLIENT_ID: &str = "test-client";
+
+/// 2048-bit RSA key signing the test tokens, published in the JWKS as `KID`
+pub(crate) const RSA_PRIVATE_KEY: &str = include_str!("../testdata/rsa_private_key.pem");
+pub(crate) const RSA_PUBLIC_KEY: &str = include_str!("../testdata/rsa_public_key.pem");
+pub(crate) const RSA_MODULUS: &str = concat!(
+    "w50wEL-wg5GGjqLrRrEgf-1Bs_LibggC89rvpgUDFx2pKBRG7nH5yAkiMYFN64t74S__F-iZeYoOS3qG",
+    "BHsOrpqFVrU7T7gRC3J-Us5qSkcSKiyU58dli_BHUoxiTnc6XoNsw4RO4pETXUASlruyIiNH7mMKK9qq",
+    "XngziDhJOb3XeBP0BpF36aW82JA5xFzSh7MBZcZcTeteM1A2sJjBNaDXXgT4vejbsMV1ATgdxXwSuerr",
+    "TOXpFbCPA3-62b7bRlg4-TsxX-i9eSSPuMuBKMCMmIFVM3YFvR8q4_lOz8T8sGXLHcxAmLI-U-dO23H4",
+    "gHAM0puY2c2hj5-HJZ9iYw",
+);
+pub(crate) const KID: &str = "test-key";
+
+/// 2048-bit RSA key replacing `RSA_PRIVATE_KEY` in rotation tests, published as `ROTATED_KID`
+pub(crate) const ROTATED_RSA_PRIVATE_KEY: &str =
+    include_str!("../testdata/rotated_rsa_private_key.pem");
+pub(crate) const ROTATED_RSA_MODULUS: &str = concat!(
+    "pSIXhloCvZ3g_C6QKZYVnBJ60ginCn5pCFS_L-ndCbnsRNK_RaSEsoktqoCf9tZDAkNFgZCpN3l7u7b8",
+    "Uihv0s73v5aP4HiyzujuQu7g_oW4CMygiUabcKzakXs0wWA-gNe9oUyXLX8S_NlDVBtQb-xGmb0B-JlE",
+    "IzZCfFWDGV_sBjuR_Zj5Qv8Ap6hdYY9v8JPHdpWSQaDZ9ZxluEqVmNAqyUqkMzudqmtb15ygIC7mV1d_",
+    "tzK2nDFQ2OG34RJggsPuQBDa8dSzufw0ni_8altmgRTCLQKQC8GiW1nH6Vc9trBHqnnVjcgtjEy6KWrE",
+    "EynYv7PKyZKRRArxe9AhLw",
+);
+pub(crate) const ROTATED_KID: &str = "rotated-key";
+
+/// Modulus of a 1024-bit RSA key, too small to be trusted
+pub(crate) const SMALL_RSA_MODULUS: &str = concat!(
+    "yF7FDphD9rRJ-HJanJb0oaXDe_4dB1zla6KDjIOOG7kfIUaFdPiEui9R2p-wKYsdjqytrBMvPNX1Zwfs",
+    "mwnPasX7ymW_BoYvWO6OJbiEmpRHKvAjk8Szxk-rBy5fzyWMcT1etkG1_EwEltvMYyGmENMU1fDmBczm",
+    "GUcz5kXXWMc",
+);
+
+/// P-256 ECDSA key, for locally provided keys
+pub(crate) const EC_PRIVATE_KEY: &str = include_str!("../testdata/ec_private_key.pem");
+pub(crate) const EC_PUBLIC_KEY: &str = include_str!("../testdata/ec_public_key.pem");
+
+/// Issuer of the tokens of the test pool
+pub(crate) fn issuer() -> String {
+    format!("https://cognito-idp.{REGION}.amazonaws.com/{POOL_ID}")
+}
+
+/// RSA JWK with the public exponent 65537
+pub(crate) fn jwk(kid: &str, alg: &str, modulus: &str) -> Value {
+    json!({ "kid": kid, "alg": alg, "kty": "RSA", "use": "sig", "n": modulus, "e": "AQAB" })
+}
+
+/// JWKS publishing `RSA_PRIVATE_KEY` as `KID`
+pub(crate) fn jwks() -> Value {
+    json!({ "keys": [jwk(KID, "RS256", RSA_MODULUS)] })
+}
+
+/// Valid claims of an ID token of the test pool, issued now and expiring in an hour
+pub(crate) fn id_claims() -> Value {
+    let now = unix_timestamp();
+    json!({
+        "sub": "8c1b2d5e-0a6f-4a8e-9f3e-2b9d3c0c1a11",
+        "cognito:username": "alice",
+        "aud": CLIENT_ID,
+        "iss": issuer(),
+        "token_use": "id",
+        "iat": now,
+        "exp": now + 3600,
+    })
+}
+
+/// Valid claims of an access token of the test pool, issued now and expiring in an hour
+pub(crate) fn access_claims() -> Value {
+    let now = unix_timestamp();
+    json!({
+        "sub": "8c1b2d5e-0a6f-4a8e-9f3e-2b9d3c0c1a11",
+        "username": "alice",
+        "client_id": CLIENT_ID,
+        "iss": issuer(),
+        "token_use": "access",
+        "scope": "openid",
+        "iat": now,
+        "exp": now + 3600,
+    })
+}
+
+/// Sign claims with a PEM encoded RSA or ECDSA private key
+pub(crate) fn sign_with(claims: &Value, header: &Value, alg: AlgorithmID, pem: &str) -> String {
+    let algorithm = match alg {
+        AlgorithmID::ES256 | AlgorithmID::ES384 => {
+            Algorithm::new_ecdsa_pem_signer(alg, pem.as_bytes())
+        }
+        _ => Algorithm::new_rsa_pem_signer(alg, pem.as_bytes()),
+    }
+    .unwrap();
+    jwt::encode(header, claims, &algorithm).unwrap()
+}
+
+/// Sign claims with `RSA_PRIVATE_KEY` using RS256
+pub(crate) fn sign(claims: &Value) -> String {
+    sign_with(
+        claims,
+        &json!({ "alg": "RS256", "kid": KID }),
+        AlgorithmID::RS256,
+        RSA_PRIVATE_KEY,
+    )
+}
+
+/// Sign claims with `ROTATED_RSA_PRIVATE_KEY` using RS256
+pub(crate) fn sign_rotated(claims: &Value) -> String {
+    sign_with(
+        claims,
+        &json!({ "alg": "RS256", "kid": ROTATED_KID }),
+        AlgorithmID::RS256,
+        ROTATED_RSA_PRIVATE_KEY,
+    )
+}
+
+/// JWKS publishing `ROTATED_RSA_PRIVATE_KEY` as `ROTATED_KID` in place of `KID`
+pub(crate) fn rotated_jwks() -> Value {
+    json!({ "keys": [jwk(ROTATED_KID, "RS256", ROTATED_RSA_MODULUS)] })
+}
+
+/// Pool configuration of the test pool, accepting tokens of `CLIENT_ID`
+pub(crate) fn pool_config() -> PoolConfig {
+    PoolConfig::new(&[CLIENT_ID], POOL_ID, REGION)
+}
+
+/// JWKS fetcher serving a JWKS that can be replaced, counting the fetches
+pub(crate) struct MockJwks {
+    jwks: Mutex<Value>,
+    fetches: AtomicUsize,
+    delay: Duration,
+}
+
+impl MockJwks {
+    pub(crate) fn new(jwks: Value) -> Arc<Self> {
+        Self::with_delay(jwks, Duration::ZERO)
+    }
+
+    /// Serve `jwks` after waiting for `delay`, so that fetches overlap
+    pub(crate) fn with_delay(jwks: Value, delay: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            jwks: Mutex::new(jwks),
+            fetches: AtomicUsize::new(0),
+            delay,
+        })
+    }
+
+    pub(crate) fn set(&self, jwks: Value) {
+        *self.jwks.lock().unwrap_or_else(PoisonError::into_inner) = jwks;
+    }
+
+    pub(crate) fn fetches(&self) -> usize {
+        self.fetches.load(Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl JwksFetcher for MockJwks {
+    async fn fetch(&self) -> Result<Value, AxumCognitoError> {
+        self.fetches.fetch_add(1, Ordering::SeqCst);
+        // The JWKS is read as the fetch starts, like a server responding after a slow network
+        let jwks = self
+            .jwks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        Ok(jwks)
+    }
+}
+
+/// Validator of the test pool fetching its JWKS from `jwks`
+pub(crate) async fn validator_with(
+    token_type: OAuthTokenType,
+    pool_config: PoolConfig,
+    jwks: Arc<MockJwks>,
+) -> CognitoValidator<Value> {
+    CognitoValidator::from_pool_config_with_jwks_fetcher(token_type, pool_config, jwks)
+        .await
+        .unwrap()
+}
+
+/// Validator of ID tokens of the test pool, using the JWKS publishing `RSA_PRIVATE_KEY`
+pub(crate) async fn id_validator() -> CognitoValidator<Value> {
+    validator_with(OAuthTokenType::Id, pool_config(), MockJwks::new(jwks())).await
+}
+
+/// Validator of access tokens of the test pool, using the JWKS publishing `RSA_PRIVATE_KEY`
+pub(crate) async fn access_validator() -> CognitoValidator<Value> {
+    validator_with(OAuthTokenType::Access, pool_config(), MockJwks::new(jwks())).await
+}
+
+/// Serve the OIDC discovery document and the JWKS of the test pool on a local port, as Cognito
+/// does
+///
+/// # Returns
+/// the endpoint of the server, to reach the pool through with `PoolConfig::with_endpoint`
+pub(crate) async fn serve_pool<R>(jwks: R) -> String
+where
+    R: IntoResponse + Clone + Send + Sync + 'static,
+{
+    let discovery = |headers: HeaderMap| async move {
+        let host = headers[header::HOST].to_str().unwrap().to_string();
+        Json(json!({ "issuer": format!("http://{host}/{POOL_ID}") }))
+    };
+    let router = Router::new()
+        .route(
+            &format!("/{POOL_ID}/.well-known/openid-configuration"),
+            get(discovery),
+        )
+        .route(
+            &format!("/{POOL_ID}/.well-known/jwks.json"),
+            get(move || async move { jwks }),
+        );
+    serve(router).await
+}
+
+/// Serve `router` on a local port
+///
+/// # Returns
+/// the base url of the server, e.g. `http://127.0.0.1:1234`
+pub(crate) async fn serve(router: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    format!("http://{address}")
+}
@@ -0,0 +1,289 @@
+use std::collections::HashSet;
+
+use axum::{async_trait, extract::FromRequestParts};
+use http::{request::Parts, StatusCode};
+use serde_json::Value;
+
+/// Output of the verification of a token
+///
+/// Inserted into the request extensions by the `CognitoAuthLayer` and available to handlers as
+/// an extractor
+#[derive(Clone, Debug)]
+pub struct VerifiedToken<UC> {
+    /// User claims extracted from the token
+    pub claims: UC,
+    /// Expiry of the token as a unix timestamp
+    pub exp: i64,
+    /// Raw claims of the token
+    pub raw: Value,
+}
+
+/// Cognito pool and client that issued a verified token
+///
+/// Inserted into the request extensions by the `CognitoAuthLayer` and available to handlers as
+/// an extractor
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthContext {
+    /// Pool id of the Cognito pool the token was verified against
+    pub pool_id: String,
+    /// AWS region of the Cognito pool the token was verified against
+    pub region: String,
+    /// Client id of the Cognito client the token was issued to
+    pub client_id: String,
+}
+
+/// Scopes granted to a verified token, parsed from its `scope` and `scp` claims
+///
+/// Inserted into the request extensions by the `CognitoAuthLayer` and available to handlers as
+/// an extractor
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Scopes(pub HashSet<String>);
+
+impl Scopes {
+    /// Parse the scopes of a token from its raw claims, empty if it has neither a `scope` nor a
+    /// `scp` claim
+    ///
+    /// Cognito uses the space-delimited `scope` claim while some AWS contexts and compatible
+    /// providers use `scp`, each claim can be a space-delimited string or an array of strings and
+    /// the scopes of both are merged
+    #[must_use]
+    pub fn from_claims(claims: &Value) -> Self {
+        let mut scopes = HashSet::new();
+        for claim in ["scope", "scp"] {
+            match claims.get(claim) {
+                Some(Value::String(scope)) => {
+                    scopes.extend(scope.split_whitespace().map(ToString::to_string));
+                }
+                Some(Value::Array(scope)) => {
+                    scopes.extend(
+                        scope
+                            .iter()
+                            .filter_map(Value::as_str)
+                            .map(ToString::to_string),
+                    );
+                }
+                _ => {}
+            }
+        }
+        Self(scopes)
+    }
+
+    /// Whether the token was granted `scope`
+    #[must_use]
+    pub fn contains(&self, scope: &str) -> bool {
+        self.0.contains(scope)
+    }
+
+    /// Whether the token was granted every one of `scopes`
+    #[must_use]
+    pub fn contains_all(&self, scopes: &[&str]) -> bool {
+        scopes.iter().all(|scope| self.contains(scope))
+    }
+}
+
+/// Username of the user a verified token was issued to, e.g. to correlate logs across services
+///
+/// Read from the `cognito:username` claim of ID tokens or the `username` claim of access tokens,
+/// falling back to the `sub` claim. Inserted into the request extensions by the
+/// `CognitoAuthLayer` and available to handlers as an extractor
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Username(pub String);
+
+impl Username {
+    /// Read the username of a token from its raw claims, `None` if it has neither a username
+    /// nor a `sub` claim
+    #[must_use]
+    pub fn from_claims(claims: &Value) -> Option<Self> {
+        ["cognito:username", "username", "sub"]
+            .into_iter()
+            .find_map(|claim| claims.get(claim).and_then(Value::as_str))
+            .map(|username| Self(username.to_string()))
+    }
+}
+
+/// Issuer of a verified token, read from its `iss` claim
+///
+/// Useful in federated scenarios to call APIs specific to the provider. Inserted into the request
+/// extensions by the `CognitoAuthLayer` and available to handlers as an extractor
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Issuer(pub String);
+
+impl Issuer {
+    /// Read the issuer of a token from its raw claims, `None` if it has no `iss` claim
+    #[must_use]
+    pub fn from_claims(claims: &Value) -> Option<Self> {
+        claims
+            .get("iss")
+            .and_then(Value::as_str)
+            .map(|issuer| Self(issuer.to_string()))
+    }
+}
+
+/// Correlation id of a request, read from a claim of its verified token, e.g. `jti`, so every log
+/// of the request can reference it
+///
+/// Inserted into the request extensions by the `CognitoAuthLayer` when configured with
+/// `with_correlation_from_claim` and available to handlers as an extractor
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorrelationId(pub String);
+
+impl CorrelationId {
+    /// Read the correlation id of a token from its `claim`, `None` if the claim is neither a
+    /// string nor a number
+    #[must_use]
+    pub fn from_claim(claims: &Value, claim: &str) -> Option<Self> {
+        match claims.get(claim)? {
+            Value::String(correlation_id) => Some(Self(correlation_id.clone())),
+            Value::Number(correlation_id) => Some(Self(correlation_id.to_string())),
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl<S, UC> FromRequestParts<S> for VerifiedToken<UC>
+where
+    UC: Clone + Send + Sync + 'static,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        from_extensions(parts)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthContext
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        from_extensions(parts)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Scopes
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        from_extensions(parts)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Username
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        from_extensions(parts)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Issuer
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        from_extensions(parts)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CorrelationId
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        from_extensions(parts)
+    }
+}
+
+fn from_extensions<T>(parts: &Parts) -> Result<T, (StatusCode, &'static str)>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    parts.extensions.get::<T>().cloned().ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Missing verified token, is the route behind a `CognitoAuthLayer`?",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn scopes_are_parsed_from_the_space_delimited_scope_claim() {
+        let scopes = Scopes::from_claims(&json!({
+            "scope": "openid  https://api.example.com/read profile"
+        }));
+
+        assert_eq!(scopes.0.len(), 3);
+        assert!(scopes.contains("https://api.example.com/read"));
+        assert!(scopes.contains_all(&["openid", "profile"]));
+        assert!(!scopes.contains_all(&["openid", "email"]));
+    }
+
+    #[test]
+    fn token_without_scope_has_no_scopes() {
+        assert_eq!(Scopes::from_claims(&json!({})), Scopes::default());
+    }
+
+    #[test]
+    fn scopes_are_parsed_from_the_scp_claim_as_a_string_or_an_array() {
+        let array = Scopes::from_claims(&json!({ "scp": ["openid", "profile"] }));
+        let string = Scopes::from_claims(&json!({ "scp": "openid profile" }));
+
+        assert!(array.contains_all(&["openid", "profile"]));
+        assert_eq!(array, string);
+    }
+
+    #[test]
+    fn scopes_of_the_scope_and_scp_claims_are_merged() {
+        let scopes = Scopes::from_claims(&json!({
+            "scope": "openid profile",
+            "scp": ["profile", "https://api.example.com/read"]
+        }));
+
+        assert_eq!(scopes.0.len(), 3);
+        assert!(scopes.contains_all(&["openid", "profile", "https://api.example.com/read"]));
+    }
+
+    #[test]
+    fn username_is_read_from_the_username_claim_of_the_token_type() {
+        assert_eq!(
+            Username::from_claims(&json!({ "cognito:username": "alice", "sub": "1234" })),
+            Some(Username("alice".to_string()))
+        );
+        assert_eq!(
+            Username::from_claims(&json!({ "username": "bob", "sub": "1234" })),
+            Some(Username("bob".to_string()))
+        );
+    }
+
+    #[test]
+    fn username_falls_back_to_the_subject() {
+        assert_eq!(
+            Username::from_claims(&json!({ "sub": "1234" })),
+            Some(Username("1234".to_string()))
+        );
+        assert_eq!(Username::from_claims(&json!({})), None);
+    }
+}
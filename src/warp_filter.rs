@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use http::{header, HeaderMap, StatusCode};
+use warp::{reject::Reject, Filter, Rejection};
+
+use crate::{bearer_token::bearer_token, CognitoValidator, VerifiedToken};
+
+/// Rejection of the requests the `cognito_auth_filter` does not authorise
+///
+/// Recover it with `Rejection::find` to turn it into a response, e.g. using `status` as the
+/// status code of the response
+#[derive(Debug)]
+pub enum CognitoRejection {
+    /// The bearer token is missing or malformed
+    BadRequest(&'static str),
+    /// The token failed verification
+    Unauthorized,
+    /// The token does not satisfy the authorization rules of its pool
+    Forbidden,
+}
+
+impl CognitoRejection {
+    /// Status code of the response the layer returns for the same failure
+    #[must_use]
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl Reject for CognitoRejection {}
+
+/// Warp filter verifying the bearer token of requests, extracting the `VerifiedToken`
+///
+/// The token is read and verified as the `CognitoAuthLayer` does and the authorization rules of
+/// the validator's pool are checked, requests that are not authorised are rejected with a
+/// `CognitoRejection`
+///
+/// # Arguments
+/// * `validator` - validator to verify tokens with
+#[must_use]
+pub fn cognito_auth_filter<UC>(
+    validator: Arc<CognitoValidator<UC>>,
+) -> impl Filter<Extract = (VerifiedToken<UC>,), Error = Rejection> + Clone
+where
+    UC: for<'de> serde::Deserialize<'de> + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    warp::any()
+        .map(move || validator.clone())
+        .and(warp::header::headers_cloned())
+        .and_then(
+            |validator: Arc<CognitoValidator<UC>>, headers: HeaderMap| async move {
+                verify(&validator, &headers)
+                    .await
+                    .map_err(warp::reject::custom)
+            },
+        )
+}
+
+async fn verify<UC>(
+    validator: &CognitoValidator<UC>,
+    headers: &HeaderMap,
+) -> Result<VerifiedToken<UC>, CognitoRejection>
+where
+    UC: for<'de> serde::Deserialize<'de> + Clone + Send + Sync + 'static + std::fmt::Debug,
+{
+    let token = bearer_token(headers, &header::AUTHORIZATION)
+        .map_err(|error| CognitoRejection::BadRequest(error.message()))?;
+    let Ok(Some(verified_token)) = validator.verify_token(token).await else {
+        return Err(CognitoRejection::Unauthorized);
+    };
+    if !validator
+        .pool_config()
+        .rules()
+        .iter()
+        .all(|rule| rule.is_satisfied_by(&verified_token.raw, None))
+    {
+        return Err(CognitoRejection::Forbidden);
+    }
+    Ok(verified_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        cognito_validator::unix_timestamp,
+        test_support::{
+            id_claims, id_validator, jwks, pool_config, sign, validator_with, MockJwks,
+        },
+        OAuthTokenType,
+    };
+
+    async fn filter_request(
+        validator: CognitoValidator<Value>,
+        authorization: &str,
+    ) -> Result<VerifiedToken<Value>, Rejection> {
+        warp::test::request()
+            .header(header::AUTHORIZATION, authorization)
+            .filter(&cognito_auth_filter(Arc::new(validator)))
+            .await
+    }
+
+    fn rejection_status(rejection: &Rejection) -> Option<StatusCode> {
+        rejection
+            .find::<CognitoRejection>()
+            .map(CognitoRejection::status)
+    }
+
+    #[tokio::test]
+    async fn verified_token_is_extracted() {
+        let token = sign(&id_claims());
+
+        let verified_token = filter_request(id_validator().await, &format!("Bearer {token}"))
+            .await
+            .unwrap();
+
+        assert_eq!(verified_token.raw["cognito:username"], "alice");
+    }
+
+    #[tokio::test]
+    async fn empty_bearer_token_is_a_bad_request() {
+        let rejection = filter_request(id_validator().await, "Bearer ")
+            .await
+            .unwrap_err();
+
+        assert_eq!(rejection_status(&rejection), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_unauthorized() {
+        let mut claims = id_claims();
+        claims["exp"] = json!(unix_timestamp() - 60);
+
+        let rejection = filter_request(id_validator().await, &format!("Bearer {}", sign(&claims)))
+            .await
+            .unwrap_err();
+
+        assert_eq!(rejection_status(&rejection), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn token_failing_the_rules_of_the_pool_is_forbidden() {
+        let validator = validator_with(
+            OAuthTokenType::Id,
+            pool_config().with_required_groups(&["admin"]),
+            MockJwks::new(jwks()),
+        )
+        .await;
+
+        let rejection = filter_request(validator, &format!("Bearer {}", sign(&id_claims())))
+            .await
+            .unwrap_err();
+
+        assert_eq!(rejection_status(&rejection), Some(StatusCode::FORBIDDEN));
+    }
+}